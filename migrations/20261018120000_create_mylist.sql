@@ -0,0 +1,8 @@
+-- `fetched` is when the entry was last read from AniDB, so changes made elsewhere are
+-- picked up
+CREATE TABLE IF NOT EXISTS mylist (
+    lid     INTEGER NOT NULL PRIMARY KEY,
+    fid     INTEGER NOT NULL UNIQUE,
+    json    TEXT NOT NULL,
+    fetched INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,10 @@
+-- Rows stay around once `done`, so a file AniDB already knows as watched (or doesn't know
+-- at all) isn't queued again on every progress report
+CREATE TABLE IF NOT EXISTS mylist_outbox (
+    fid             INTEGER NOT NULL PRIMARY KEY,
+    viewdate        INTEGER NOT NULL,
+    attempts        INTEGER NOT NULL DEFAULT 0,
+    last_error      TEXT,
+    next_attempt    INTEGER NOT NULL,
+    done            INTEGER NOT NULL DEFAULT 0
+);
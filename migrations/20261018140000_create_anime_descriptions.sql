@@ -0,0 +1,7 @@
+-- A NULL description means AniDB has none. Remembering that saves an ANIMEDESC request
+-- every time such an anime is shown, until `fetched` is old enough to ask again.
+CREATE TABLE IF NOT EXISTS anime_descriptions (
+    aid             INTEGER NOT NULL PRIMARY KEY,
+    description     TEXT,
+    fetched         INTEGER NOT NULL DEFAULT 0
+);
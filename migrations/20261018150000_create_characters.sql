@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS characters (
+    charid  INTEGER NOT NULL PRIMARY KEY,
+    json    TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS creators (
+    creatorid   INTEGER NOT NULL PRIMARY KEY,
+    json        TEXT NOT NULL
+);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS anime_titles (
+    aid         INTEGER NOT NULL,
+    type        TEXT NOT NULL,
+    language    TEXT NOT NULL,
+    title       TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS anime_titles_aid ON anime_titles (aid);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS anime_titles_fts USING fts5 (
+    title,
+    content = 'anime_titles',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
@@ -0,0 +1,4 @@
+-- Best guess from the file name for files AniDB doesn't know by hash
+ALTER TABLE indexed_files ADD COLUMN guessed_aid INTEGER;
+ALTER TABLE indexed_files ADD COLUMN guessed_eid INTEGER;
+ALTER TABLE indexed_files ADD COLUMN confidence REAL;
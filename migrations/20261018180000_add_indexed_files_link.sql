@@ -0,0 +1,4 @@
+-- What the user told us a file is, for files AniDB doesn't know by hash
+ALTER TABLE indexed_files ADD COLUMN linked_aid INTEGER;
+ALTER TABLE indexed_files ADD COLUMN linked_eid INTEGER;
+ALTER TABLE indexed_files ADD COLUMN linked_gid INTEGER;
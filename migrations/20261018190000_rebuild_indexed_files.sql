@@ -0,0 +1,35 @@
+-- UNIQUE (filename, filesize) ON CONFLICT REPLACE silently dropped rows for different files
+-- that happened to share a name and size. Rebuild the table without it, and with what we
+-- need to recognize files that moved or disappeared.
+CREATE TABLE indexed_files_new (
+    path                TEXT NOT NULL PRIMARY KEY,
+    filename            TEXT NOT NULL,
+    filesize            INTEGER NOT NULL,
+    ed2k                TEXT NOT NULL,
+    fid                 INTEGER,
+    first_seen          INTEGER NOT NULL,
+    last_updated        INTEGER NOT NULL,
+    guessed_aid         INTEGER,
+    guessed_eid         INTEGER,
+    confidence          REAL,
+    linked_aid          INTEGER,
+    linked_eid          INTEGER,
+    linked_gid          INTEGER,
+    inode               INTEGER,
+    mtime               INTEGER,
+    missing_since       INTEGER
+);
+
+INSERT INTO indexed_files_new (
+    path, filename, filesize, ed2k, fid, first_seen, last_updated,
+    guessed_aid, guessed_eid, confidence, linked_aid, linked_eid, linked_gid
+)
+SELECT
+    path, filename, filesize, ed2k, fid, first_seen, last_updated,
+    guessed_aid, guessed_eid, confidence, linked_aid, linked_eid, linked_gid
+FROM indexed_files;
+
+DROP TABLE indexed_files;
+ALTER TABLE indexed_files_new RENAME TO indexed_files;
+
+CREATE INDEX IF NOT EXISTS indexed_files_filesize ON indexed_files (filesize);
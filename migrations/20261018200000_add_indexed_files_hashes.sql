@@ -0,0 +1,4 @@
+-- Only filled when indexing with extra hashes enabled
+ALTER TABLE indexed_files ADD COLUMN crc32 TEXT;
+ALTER TABLE indexed_files ADD COLUMN md5 TEXT;
+ALTER TABLE indexed_files ADD COLUMN sha1 TEXT;
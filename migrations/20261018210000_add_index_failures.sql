@@ -0,0 +1,7 @@
+-- Files an indexing run couldn't finish, for `tetsu index --retry-failed`
+CREATE TABLE IF NOT EXISTS index_failures (
+    path        TEXT NOT NULL PRIMARY KEY,
+    reason      TEXT NOT NULL,
+    attempts    INTEGER NOT NULL DEFAULT 1,
+    failed_at   INTEGER NOT NULL
+);
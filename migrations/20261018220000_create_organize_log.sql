@@ -0,0 +1,12 @@
+-- Every file `tetsu organize` moved or linked, so a run can be undone
+CREATE TABLE IF NOT EXISTS organize_log (
+    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    run         INTEGER NOT NULL,
+    mode        TEXT NOT NULL,
+    root        TEXT NOT NULL,
+    source      TEXT NOT NULL,
+    target      TEXT NOT NULL,
+    undone      INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS organize_log_run ON organize_log (run);
@@ -0,0 +1,4 @@
+-- Records fetched with a narrowed mask because the full one didn't fit in a packet. They
+-- lack the dropped fields, so they're fetched again instead of served from the cache.
+ALTER TABLE files ADD COLUMN partial INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE anime ADD COLUMN partial INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,29 @@
+-- Group names as columns, so release names can be checked against them without reading
+-- every cached group
+ALTER TABLE groups ADD COLUMN name TEXT NOT NULL DEFAULT '';
+ALTER TABLE groups ADD COLUMN short TEXT NOT NULL DEFAULT '';
+
+UPDATE groups SET
+    name = coalesce(json_extract(json, '$.name'), ''),
+    short = coalesce(json_extract(json, '$.short'), '');
+
+CREATE INDEX IF NOT EXISTS groups_name ON groups (name COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS groups_short ON groups (short COLLATE NOCASE);
+
+-- Titles of cached anime go into the title index too, for when the dump hasn't been
+-- imported or predates them
+INSERT INTO anime_titles (aid, type, language, title)
+SELECT aid, type, language, title FROM (
+    SELECT aid, 'main' AS type, 'x-jat' AS language, json_extract(json, '$.romaji_name') AS title
+    FROM anime
+    UNION
+    SELECT aid, 'official', 'en', json_extract(json, '$.english_name') FROM anime
+    UNION
+    SELECT aid, 'official', 'ja', json_extract(json, '$.kanji_name') FROM anime
+    UNION
+    SELECT anime.aid, 'short', '', short.value FROM anime, json_each(anime.json, '$.short_name_list') short
+) cached
+WHERE coalesce(title, '') != ''
+    AND NOT EXISTS (SELECT 1 FROM anime_titles t WHERE t.aid = cached.aid AND t.title = cached.title);
+
+INSERT INTO anime_titles_fts (anime_titles_fts) VALUES ('rebuild');
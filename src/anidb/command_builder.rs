@@ -1,5 +1,6 @@
 use std::fmt::{Display, Write};
 
+#[derive(Clone)]
 pub struct CommandBuilder {
     name: String,
     args: Vec<(String, String)>,
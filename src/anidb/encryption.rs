@@ -0,0 +1,50 @@
+//! AES-128-ECB as used by the ENCRYPT command. The key is the MD5 of the user's API key
+//! followed by the salt AniDB hands out, and every packet after that is encrypted in
+//! both directions until the session ends.
+
+use aes::{
+    cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyInit},
+    Aes128,
+};
+use anyhow::{anyhow, Result};
+use md5::{Digest, Md5};
+
+#[derive(Clone)]
+pub struct Cipher {
+    key: [u8; 16],
+}
+
+impl Cipher {
+    pub fn new(api_key: &str, salt: &str) -> Self {
+        let mut hasher = Md5::new();
+        hasher.update(api_key.as_bytes());
+        hasher.update(salt.as_bytes());
+
+        Self { key: hasher.finalize().into() }
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        ecb::Encryptor::<Aes128>::new(&self.key.into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        ecb::Decryptor::<Aes128>::new(&self.key.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .map_err(|_| anyhow!("Failed to decrypt packet"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::new("secret", "abcde");
+        let packet = cipher.encrypt(b"FILE fid=1&s=abcde");
+
+        assert_eq!(packet.len() % 16, 0);
+        assert_eq!(cipher.decrypt(&packet).unwrap(), b"FILE fid=1&s=abcde");
+        assert!(Cipher::new("wrong", "abcde").decrypt(&packet).is_err());
+    }
+}
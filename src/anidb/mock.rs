@@ -0,0 +1,548 @@
+//! An in-process stand-in for the AniDB UDP API, serving canned records so the
+//! session, cache and indexer can be exercised without the real service.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use num_traits::FromPrimitive;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use super::{
+    encryption::Cipher,
+    records::{
+        Anime, Character, Creator, Episode, File, Group, Mask, MaskedRecord, MylistEntry,
+        MylistState, Record,
+    },
+    response::codes::ResponseCode,
+};
+use crate::{config::AnidbConfig, db::settings};
+
+const MAX_PACKET: usize = 1400;
+/// Characters per ANIMEDESC part
+const DESCRIPTION_PART: usize = 1000;
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct MockState {
+    sessions: HashSet<String>,
+    files: Vec<(File, String)>,
+    anime: HashMap<u32, String>,
+    descriptions: HashMap<u32, String>,
+    characters: HashMap<u32, String>,
+    creators: HashMap<u32, String>,
+    episodes: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+    mylist: Vec<MylistEntry>,
+    requests: Vec<String>,
+    unencrypted: Vec<String>,
+    api_key: Option<String>,
+    cipher: Option<Cipher>,
+}
+
+impl MockServer {
+    pub async fn start() -> Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .context("Failed to bind mock AniDB socket")?;
+
+        let addr = socket.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let task = tokio::spawn({
+            let state = state.clone();
+
+            async move {
+                let mut buf = [0; MAX_PACKET];
+
+                while let Ok((read, peer)) = socket.recv_from(&mut buf).await {
+                    let res = state.lock().unwrap().handle_packet(&buf[..read]);
+
+                    if let Err(e) = socket.send_to(&res, peer).await {
+                        log::error!("Mock AniDB failed to respond: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// A server, with credentials for it stored in the settings so sessions can log in
+    pub async fn logged_in() -> Self {
+        let server = Self::start().await.unwrap();
+
+        settings::anidb::set_username("tetsu".to_string())
+            .await
+            .unwrap();
+        settings::anidb::set_password("hunter2".to_string())
+            .await
+            .unwrap();
+
+        server
+    }
+
+    /// Config that points a session at this server
+    pub fn config(&self) -> AnidbConfig {
+        AnidbConfig {
+            server: self.addr.to_string(),
+            bind: "127.0.0.1:0".to_string(),
+            short_term_interval: 0.,
+            long_term_interval: 0.,
+            ..Default::default()
+        }
+    }
+
+    /// Every command received so far, including the ones that were rejected
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Commands that arrived in plain text
+    pub fn unencrypted_requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().unencrypted.clone()
+    }
+
+    /// Allow ENCRYPT for any user, with this API key
+    pub fn set_api_key(&self, api_key: &str) {
+        self.state.lock().unwrap().api_key = Some(api_key.to_string());
+    }
+
+    /// Drop every session along with the encryption, like AniDB does after a while
+    pub fn forget_sessions(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.sessions.clear();
+        state.cipher = None;
+    }
+
+    pub fn add_file(&self, record: &str) -> Result<()> {
+        let file = File::parse(record)?;
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .push((file, record.to_string()));
+        Ok(())
+    }
+
+    pub fn add_anime(&self, record: &str) -> Result<()> {
+        let anime = Anime::parse(record)?;
+        self.state
+            .lock()
+            .unwrap()
+            .anime
+            .insert(anime.aid, record.to_string());
+        Ok(())
+    }
+
+    pub fn add_description(&self, aid: u32, description: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .descriptions
+            .insert(aid, description.to_string());
+    }
+
+    pub fn add_character(&self, record: &str) -> Result<()> {
+        let character = Character::parse(record)?;
+        self.state
+            .lock()
+            .unwrap()
+            .characters
+            .insert(character.charid, record.to_string());
+        Ok(())
+    }
+
+    pub fn add_creator(&self, record: &str) -> Result<()> {
+        let creator = Creator::parse(record)?;
+        self.state
+            .lock()
+            .unwrap()
+            .creators
+            .insert(creator.creatorid, record.to_string());
+        Ok(())
+    }
+
+    pub fn add_episode(&self, record: &str) -> Result<()> {
+        let episode = Episode::parse(record)?;
+        self.state
+            .lock()
+            .unwrap()
+            .episodes
+            .insert(episode.eid, record.to_string());
+        Ok(())
+    }
+
+    pub fn add_group(&self, record: &str) -> Result<()> {
+        let group = Group::parse(record)?;
+        self.state
+            .lock()
+            .unwrap()
+            .groups
+            .insert(group.gid, record.to_string());
+        Ok(())
+    }
+
+    /// Current state of the user's mylist, as the server sees it
+    pub fn mylist(&self) -> Vec<MylistEntry> {
+        self.state.lock().unwrap().mylist.clone()
+    }
+
+    /// Remove a file from the mylist, like the user would on the website
+    pub fn remove_from_mylist(&self, fid: u32) {
+        self.state.lock().unwrap().mylist.retain(|e| e.fid != fid);
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl MockState {
+    fn handle_packet(&mut self, packet: &[u8]) -> Vec<u8> {
+        // replies use the cipher the request came in with, even if it just changed
+        let mut res = match self.cipher.clone() {
+            None => {
+                let req = String::from_utf8_lossy(packet).to_string();
+                self.unencrypted.push(req.trim_end().to_string());
+                self.handle(&req).into_bytes()
+            }
+            Some(cipher) => match cipher.decrypt(packet) {
+                Ok(req) => cipher.encrypt(self.handle(&String::from_utf8_lossy(&req)).as_bytes()),
+                Err(_) => reply(ResponseCode::UnknownCommand, "UNKNOWN COMMAND", None).into_bytes(),
+            },
+        };
+
+        // like the real thing, cut off whatever doesn't fit in a packet
+        res.truncate(MAX_PACKET);
+        res
+    }
+
+    fn handle(&mut self, req: &str) -> String {
+        let req = req.trim_end();
+        self.requests.push(req.to_string());
+
+        let (name, args) = parse_command(req);
+
+        if !["PING", "ENCRYPT", "AUTH"].contains(&name) {
+            match args.get("s") {
+                None => return reply(ResponseCode::LoginFirst, "LOGIN FIRST", None),
+                Some(key) if !self.sessions.contains(key) => {
+                    return reply(ResponseCode::InvalidSession, "INVALID SESSION", None)
+                }
+                Some(_) => (),
+            }
+        }
+
+        let arg = |key: &str| args.get(key).and_then(|v| v.parse::<u32>().ok());
+
+        match name {
+            "PING" => reply(ResponseCode::Pong, "PONG", None),
+            "AUTH" => {
+                let key = format!("mock{}", self.sessions.len() + 1);
+                let res =
+                    reply(ResponseCode::LoginAccepted, &format!("{key} LOGIN ACCEPTED"), None);
+                self.sessions.insert(key);
+                res
+            }
+            "ENCRYPT" => {
+                let Some(ref api_key) = self.api_key else {
+                    return reply(
+                        ResponseCode::ApiPasswordNotDefined,
+                        "API PASSWORD NOT DEFINED",
+                        None,
+                    );
+                };
+
+                if arg("type") != Some(1) {
+                    return reply(
+                        ResponseCode::NoSuchEncryptionType,
+                        "NO SUCH ENCRYPTION TYPE",
+                        None,
+                    );
+                }
+
+                let salt = format!("salt{}", self.sessions.len() + 1);
+                self.cipher = Some(Cipher::new(api_key, &salt));
+                reply(ResponseCode::EncryptionEnabled, &format!("{salt} ENCRYPTION ENABLED"), None)
+            }
+            "LOGOUT" => {
+                self.sessions.remove(&args["s"]);
+                self.cipher = None;
+                reply(ResponseCode::LoggedOut, "LOGGED OUT", None)
+            }
+            "FILE" => {
+                let size = args.get("size").and_then(|v| v.parse::<i64>().ok());
+                let ed2k = args.get("ed2k");
+
+                let record = self.files.iter().find_map(|(file, record)| {
+                    let by_fid = arg("fid") == Some(file.fid);
+                    let by_hash = size == Some(file.size) && ed2k == Some(&file.ed2k);
+                    (by_fid || by_hash).then_some(record)
+                });
+
+                match record {
+                    Some(record) => {
+                        let record = masked::<File>(record, args.get("fmask"), 1);
+                        reply(ResponseCode::File, "FILE", Some(&record))
+                    }
+                    None => reply(ResponseCode::NoSuchFile, "NO SUCH FILE", None),
+                }
+            }
+            "ANIME" => match arg("aid").and_then(|aid| self.anime.get(&aid)) {
+                Some(record) => {
+                    let record = masked::<Anime>(record, args.get("amask"), 0);
+                    reply(ResponseCode::Anime, "ANIME", Some(&record))
+                }
+                None => reply(ResponseCode::NoSuchAnime, "NO SUCH ANIME", None),
+            },
+            "ANIMEDESC" => {
+                let Some(description) = arg("aid").and_then(|aid| self.descriptions.get(&aid))
+                else {
+                    return reply(ResponseCode::NoSuchDescription, "NO SUCH DESCRIPTION", None);
+                };
+
+                let parts: Vec<String> = description
+                    .chars()
+                    .collect::<Vec<_>>()
+                    .chunks(DESCRIPTION_PART)
+                    .map(|chunk| chunk.iter().collect())
+                    .collect();
+
+                let part = arg("part").unwrap_or_default() as usize;
+                match parts.get(part) {
+                    Some(text) => {
+                        let record = format!("{part}|{}|{text}", parts.len());
+                        reply(ResponseCode::AnimeDescription, "ANIMEDESC", Some(&record))
+                    }
+                    None => reply(ResponseCode::NoSuchDescription, "NO SUCH DESCRIPTION", None),
+                }
+            }
+            "CHARACTER" => match arg("charid").and_then(|id| self.characters.get(&id)) {
+                Some(record) => reply(ResponseCode::Character, "CHARACTER", Some(record)),
+                None => reply(ResponseCode::NoSuchCharacter, "NO SUCH CHARACTER", None),
+            },
+            "CREATOR" => match arg("creatorid").and_then(|id| self.creators.get(&id)) {
+                Some(record) => reply(ResponseCode::Creator, "CREATOR", Some(record)),
+                None => reply(ResponseCode::NoSuchCreator, "NO SUCH CREATOR", None),
+            },
+            "EPISODE" => match arg("eid")
+                .and_then(|eid| self.episodes.get(&eid))
+                .or_else(|| {
+                    let (aid, epno) = (arg("aid")?, args.get("epno")?);
+                    self.episodes.values().find(|record| {
+                        Episode::parse(record).is_ok_and(|e| e.aid == aid && e.has_epno(epno))
+                    })
+                }) {
+                Some(record) => reply(ResponseCode::Episode, "EPISODE", Some(record)),
+                None => reply(ResponseCode::NoSuchEpisode, "NO SUCH EPISODE", None),
+            },
+            "GROUP" => match arg("gid").and_then(|gid| self.groups.get(&gid)) {
+                Some(record) => reply(ResponseCode::Group, "GROUP", Some(record)),
+                None => reply(ResponseCode::NoSuchGroup, "NO SUCH GROUP", None),
+            },
+            "MYLIST" => {
+                let entry = self
+                    .mylist
+                    .iter()
+                    .find(|e| arg("lid") == Some(e.lid) || arg("fid") == Some(e.fid));
+
+                match entry {
+                    Some(entry) => {
+                        reply(ResponseCode::Mylist, "MYLIST", Some(&mylist_record(entry)))
+                    }
+                    None => reply(ResponseCode::NoSuchEntry, "NO SUCH ENTRY", None),
+                }
+            }
+            "MYLISTADD" => self.mylist_add(&args),
+            "MYLISTDEL" => {
+                let before = self.mylist.len();
+                self.mylist.retain(|e| arg("lid") != Some(e.lid));
+
+                if self.mylist.len() == before {
+                    reply(ResponseCode::NoSuchMylistEntry, "NO SUCH MYLIST ENTRY", None)
+                } else {
+                    reply(
+                        ResponseCode::MylistEntryDeleted,
+                        "MYLIST ENTRY DELETED",
+                        Some(&"1".to_string()),
+                    )
+                }
+            }
+            _ => reply(ResponseCode::UnknownCommand, "UNKNOWN COMMAND", None),
+        }
+    }
+
+    fn mylist_add(&mut self, args: &HashMap<String, String>) -> String {
+        let arg = |key: &str| args.get(key).and_then(|v| v.parse::<i64>().ok());
+
+        let state = arg("state").and_then(MylistState::from_i64);
+        let viewdate = match (arg("viewed"), arg("viewdate")) {
+            (Some(0), _) => Some(DateTime::UNIX_EPOCH),
+            (_, Some(ts)) => DateTime::from_timestamp(ts, 0),
+            (Some(_), None) => Some(Utc::now()),
+            (None, None) => None,
+        };
+
+        if arg("edit") == Some(1) {
+            let Some(entry) = self
+                .mylist
+                .iter_mut()
+                .find(|e| arg("lid") == Some(e.lid as i64) || arg("fid") == Some(e.fid as i64))
+            else {
+                return reply(ResponseCode::NoSuchMylistEntry, "NO SUCH MYLIST ENTRY", None);
+            };
+
+            entry.state = state.unwrap_or(entry.state);
+            entry.viewdate = viewdate.unwrap_or(entry.viewdate);
+
+            return reply(
+                ResponseCode::MylistEntryEdited,
+                "MYLIST ENTRY EDITED",
+                Some(&"1".to_string()),
+            );
+        }
+
+        let Some((file, _)) = self
+            .files
+            .iter()
+            .find(|(f, _)| arg("fid") == Some(f.fid as i64))
+        else {
+            return reply(ResponseCode::NoSuchFile, "NO SUCH FILE", None);
+        };
+
+        if let Some(entry) = self.mylist.iter().find(|e| e.fid == file.fid) {
+            return reply(
+                ResponseCode::FileAlreadyInMylist,
+                "FILE ALREADY IN MYLIST",
+                Some(&mylist_record(entry)),
+            );
+        }
+
+        let lid = self.mylist.iter().map(|e| e.lid).max().unwrap_or_default() + 1;
+
+        self.mylist.push(MylistEntry {
+            lid,
+            fid: file.fid,
+            eid: file.eid,
+            aid: file.aid,
+            gid: file.gid,
+            date: Utc::now(),
+            state: state.unwrap_or(MylistState::Unknown),
+            viewdate: viewdate.unwrap_or_default(),
+            storage: String::new(),
+            source: String::new(),
+            other: String::new(),
+            filestate: 0,
+        });
+
+        reply(ResponseCode::MylistEntryAdded, "MYLIST ENTRY ADDED", Some(&lid.to_string()))
+    }
+}
+
+/// Keep the columns of a record stored with the default mask that were asked for.
+/// The first `fixed` columns are always sent.
+fn masked<R: MaskedRecord>(record: &str, mask: Option<&String>, fixed: usize) -> String {
+    let Some(mask) = mask.and_then(|mask| mask.parse::<Mask<R::Field>>().ok()) else {
+        return record.to_string();
+    };
+
+    let mut columns = record.split('|');
+    let mut kept: Vec<_> = columns.by_ref().take(fixed).collect();
+
+    for (field, column) in R::default_mask().fields().zip(columns) {
+        if mask.contains(field) {
+            kept.push(column);
+        }
+    }
+
+    kept.join("|")
+}
+
+fn mylist_record(entry: &MylistEntry) -> String {
+    format!(
+        "{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+        entry.lid,
+        entry.fid,
+        entry.eid,
+        entry.aid,
+        entry.gid,
+        entry.date.timestamp(),
+        entry.state as u8,
+        entry.viewdate.timestamp(),
+        entry.storage,
+        entry.source,
+        entry.other,
+        entry.filestate,
+    )
+}
+
+fn parse_command(req: &str) -> (&str, HashMap<String, String>) {
+    let (name, args) = req.split_once(' ').unwrap_or((req, ""));
+
+    let args = args
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.replace("&amp;", "&").replace("<br />", "\n")))
+        .collect();
+
+    (name, args)
+}
+
+fn reply(code: ResponseCode, message: &str, record: Option<&String>) -> String {
+    match record {
+        Some(record) => format!("{} {message}\n{record}\n", code as u32),
+        None => format!("{} {message}\n", code as u32),
+    }
+}
+
+/// Canned records that reference each other: file -> anime 1, episode 1, group 1,
+/// and anime 1 -> character 1 -> creator 1
+pub mod fixtures {
+    pub const ANIME: &str = "1|0|1999-1999|TV Series|4'6|2'1|Seikai no Monshou|星界の紋章|Crest of the Stars|CotS|13|3|946684800|948844800|1.jpg|0|147|1|3|0|0|0|0";
+    pub const EPISODE: &str = "1|1|25|800|12|1|Invasion|Shinryaku|侵略|946684800|1";
+    pub const CHARACTER: &str = "1|ラフィール|Lafiel|1.jpg|1,1,1,1|1,2|946684800|1|F";
+    pub const CREATOR: &str = "1|川澄綾子|Kawasumi Ayako|1|1.jpg|||||946684800";
+    pub const GROUP: &str = "1|750|30|12|200|Tetsu Fansubs|TF|#tetsu|irc.rizon.net|https://example.com|1.png|946684800|0|0|946684800|946684800|";
+
+    pub fn file(fid: u32, size: i64, ed2k: &str) -> String {
+        file_with_hashes(fid, size, ed2k, "", "", "")
+    }
+
+    pub fn file_with_hashes(
+        fid: u32,
+        size: i64,
+        ed2k: &str,
+        md5: &str,
+        sha1: &str,
+        crc32: &str,
+    ) -> String {
+        format!("{fid}|1|1|1|1|{size}|{ed2k}|{md5}|{sha1}|{crc32}|8|high|DVD|AAC|128|H264/AVC|1500|1280x720|japanese|english|1500||946684800")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_session() {
+        let mut state = MockState::default();
+
+        assert_eq!(state.handle("ANIME aid=1\n"), "501 LOGIN FIRST\n");
+        assert_eq!(state.handle("ANIME aid=1&s=nope\n"), "506 INVALID SESSION\n");
+        assert_eq!(state.handle("AUTH user=a&pass=b&amp;c\n"), "200 mock1 LOGIN ACCEPTED\n");
+        assert_eq!(state.handle("ANIME aid=1&s=mock1\n"), "330 NO SUCH ANIME\n");
+    }
+}
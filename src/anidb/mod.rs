@@ -1,16 +1,42 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
 use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use lazy_static::lazy_static;
 
+pub use self::scheduler::{Priority, RateLimitState, RateLimiter, RateLimits};
 use self::{
-    records::{Anime, Episode, File},
+    records::{Anime, Character, Creator, Episode, File, MylistEntry, MylistState},
+    scheduler::{PriorityGuard, PriorityMutex},
     session::Session,
 };
-use crate::db::settings;
+use crate::{config::AnidbConfig, db::settings};
 
 mod command_builder;
+mod encryption;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod outbox;
 pub mod records;
+pub mod relations;
+pub mod titles;
 mod response;
+mod scheduler;
 mod session;
 
+/// How long to trust that AniDB has no description for an anime
+const NO_DESCRIPTION_RECHECK: TimeDelta = TimeDelta::days(7);
+
+/// How long a cached mylist entry is trusted, it can be edited on the website
+const MYLIST_MAX_AGE: TimeDelta = TimeDelta::days(1);
+
+lazy_static! {
+    static ref SHARED: Arc<Shared> = Arc::new(Shared::new(None));
+}
+
 pub async fn login() -> Result<()> {
     let username = dialoguer::Input::new()
         .with_prompt("Username")
@@ -22,71 +48,241 @@ pub async fn login() -> Result<()> {
         .interact()
         .context("Failed to read password")?;
 
+    let api_key: String = dialoguer::Password::new()
+        .with_prompt("API key (optional, enables encryption)")
+        .allow_empty_password(true)
+        .interact()
+        .context("Failed to read API key")?;
+
     settings::anidb::set_username(username).await?;
     settings::anidb::set_password(password).await?;
 
+    if !api_key.is_empty() {
+        settings::anidb::set_api_key(api_key).await?;
+    }
+
     Ok(())
 }
 
+/// Fields to set when adding or editing a mylist entry; `None` leaves them as they are
+#[derive(Debug, Clone, Default)]
+pub struct MylistUpdate {
+    pub state: Option<MylistState>,
+    pub viewed: Option<bool>,
+    pub viewdate: Option<DateTime<Utc>>,
+}
+
+impl MylistUpdate {
+    fn apply(&self, entry: &mut MylistEntry) {
+        if let Some(state) = self.state {
+            entry.state = state;
+        }
+
+        match (self.viewed, self.viewdate) {
+            (Some(false), _) => entry.viewdate = DateTime::UNIX_EPOCH,
+            (_, Some(viewdate)) => entry.viewdate = viewdate,
+            (Some(true), None) if !entry.viewed() => entry.viewdate = Utc::now(),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MylistAdd {
+    Added(MylistEntry),
+    AlreadyInMylist(MylistEntry),
+    NoSuchFile,
+}
+
+/// A single AniDB session per endpoint, shared by every [`Anidb`] handle talking to it,
+/// so they all count against the same rate limit
+struct Shared {
+    config: Option<AnidbConfig>,
+    session: PriorityMutex<Option<Session>>,
+}
+
+impl Shared {
+    fn new(config: Option<AnidbConfig>) -> Self {
+        Self {
+            config,
+            session: PriorityMutex::new(None),
+        }
+    }
+}
+
+struct SessionGuard<'a>(PriorityGuard<'a, Option<Session>>);
+
+impl Deref for SessionGuard<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for SessionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Session {
+        self.0.as_mut().unwrap()
+    }
+}
+
+#[derive(Clone)]
 pub struct Anidb {
-    session: Option<Session>,
+    shared: Arc<Shared>,
+    priority: Priority,
+}
+
+impl Default for Anidb {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Anidb {
     pub fn new() -> Self {
-        Self { session: None }
+        Self {
+            shared: SHARED.clone(),
+            priority: Priority::Normal,
+        }
     }
 
-    async fn session(&mut self) -> Result<&mut Session> {
-        if let Some(ref mut session) = self.session {
-            return Ok(session);
+    /// Talk to a specific AniDB endpoint instead of the one from the config file
+    pub fn with_config(config: AnidbConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared::new(Some(config))),
+            priority: Priority::Normal,
         }
+    }
 
-        self.session = Some(Session::new().await?);
-        Ok(self.session.as_mut().unwrap())
+    /// Requests from handles with a higher priority are sent first when several are waiting
+    pub fn with_priority(self, priority: Priority) -> Self {
+        Self { priority, ..self }
     }
 
-    pub async fn file_by_ed2k(&mut self, size: i64, hash: &str) -> Result<Option<File>> {
-        let cache =
-            sqlx::query!("SELECT json FROM files WHERE size = $1 AND ed2k = $2", size, hash)
-                .fetch_optional(crate::DB.get().await)
-                .await?;
+    async fn session(&mut self) -> Result<SessionGuard<'_>> {
+        let mut guard = self.shared.session.lock(self.priority).await;
 
-        if let Some(file) = cache {
-            return Ok(Some(
-                serde_json::from_str(&file.json).context("Invalid record in database")?,
-            ));
+        if guard.is_none() {
+            *guard = Some(match self.shared.config {
+                Some(ref config) => Session::connect(config).await?,
+                None => Session::new().await?,
+            });
+        }
+
+        Ok(SessionGuard(guard))
+    }
+
+    /// Log out of the session, if one was opened
+    pub async fn logout(&mut self) -> Result<()> {
+        match *self.shared.session.lock(self.priority).await {
+            Some(ref mut session) => session.logout().await,
+            None => Ok(()),
         }
+    }
+
+    pub async fn file_by_ed2k(&mut self, size: i64, hash: &str) -> Result<Option<File>> {
+        let cache = sqlx::query!(
+            "SELECT json, partial FROM files WHERE size = $1 AND ed2k = $2",
+            size,
+            hash
+        )
+        .fetch_optional(crate::DB.get().await)
+        .await?;
 
-        self.session().await?.file_by_ed2k(size, hash).await
+        let cached = match cache {
+            Some(file) if file.partial == 0 => return parse_cached(&file.json).map(Some),
+            Some(file) => Some(file.json),
+            None => None,
+        };
+
+        let fetched = self.session().await?.file_by_ed2k(size, hash).await;
+        or_partial(fetched, cached)
     }
 
     pub async fn file_by_fid(&mut self, fid: u32) -> Result<Option<File>> {
-        let cache = sqlx::query!("SELECT json FROM files WHERE fid = $1", fid)
+        let cache = sqlx::query!("SELECT json, partial FROM files WHERE fid = $1", fid)
             .fetch_optional(crate::DB.get().await)
             .await?;
 
-        if let Some(file) = cache {
-            return Ok(Some(
-                serde_json::from_str(&file.json).context("Invalid record in database")?,
-            ));
-        }
+        let cached = match cache {
+            Some(file) if file.partial == 0 => return parse_cached(&file.json).map(Some),
+            Some(file) => Some(file.json),
+            None => None,
+        };
 
-        self.session().await?.file_by_fid(fid).await
+        let fetched = self.session().await?.file_by_fid(fid).await;
+        or_partial(fetched, cached)
     }
 
     pub async fn anime_by_aid(&mut self, aid: u32) -> Result<Option<Anime>> {
-        let cache = sqlx::query!("SELECT json FROM anime WHERE aid = $1", aid)
+        let cache = sqlx::query!("SELECT json, partial FROM anime WHERE aid = $1", aid)
             .fetch_optional(crate::DB.get().await)
             .await?;
 
-        if let Some(anime) = cache {
-            return Ok(Some(
-                serde_json::from_str(&anime.json).context("Invalid record in database")?,
-            ));
+        let cached = match cache {
+            Some(anime) if anime.partial == 0 => return parse_cached(&anime.json).map(Some),
+            Some(anime) => Some(anime.json),
+            None => None,
+        };
+
+        let fetched = self.session().await?.anime_by_aid(aid).await;
+        or_partial(fetched, cached)
+    }
+
+    pub async fn anime_description(&mut self, aid: u32) -> Result<Option<String>> {
+        let cache = sqlx::query!(
+            "SELECT description, fetched FROM anime_descriptions WHERE aid = $1",
+            aid
+        )
+        .fetch_optional(crate::DB.get().await)
+        .await?;
+
+        if let Some(row) = cache {
+            let fetched = DateTime::from_timestamp(row.fetched, 0).unwrap_or_default();
+
+            // one may have been written since
+            if row.description.is_some() || Utc::now() - fetched < NO_DESCRIPTION_RECHECK {
+                return Ok(row.description);
+            }
         }
 
-        self.session().await?.anime_by_aid(aid).await
+        self.session().await?.anime_description(aid).await
+    }
+
+    pub async fn character_by_id(&mut self, charid: u32) -> Result<Option<Character>> {
+        if let Some(character) = self.cached_character(charid).await? {
+            return Ok(Some(character));
+        }
+
+        self.session().await?.character_by_id(charid).await
+    }
+
+    /// The character if it's cached, without asking AniDB otherwise
+    pub async fn cached_character(&self, charid: u32) -> Result<Option<Character>> {
+        let cache = sqlx::query!("SELECT json FROM characters WHERE charid = $1", charid)
+            .fetch_optional(crate::DB.get().await)
+            .await?;
+
+        cache
+            .map(|character| parse_cached(&character.json))
+            .transpose()
+    }
+
+    pub async fn creator_by_id(&mut self, creatorid: u32) -> Result<Option<Creator>> {
+        if let Some(creator) = self.cached_creator(creatorid).await? {
+            return Ok(Some(creator));
+        }
+
+        self.session().await?.creator_by_id(creatorid).await
+    }
+
+    /// The creator if it's cached, without asking AniDB otherwise
+    pub async fn cached_creator(&self, creatorid: u32) -> Result<Option<Creator>> {
+        let cache = sqlx::query!("SELECT json FROM creators WHERE creatorid = $1", creatorid)
+            .fetch_optional(crate::DB.get().await)
+            .await?;
+
+        cache.map(|creator| parse_cached(&creator.json)).transpose()
     }
 
     pub async fn episode_by_eid(&mut self, eid: u32) -> Result<Option<Episode>> {
@@ -103,6 +299,23 @@ impl Anidb {
         self.session().await?.episode_by_eid(eid).await
     }
 
+    pub async fn episode_by_epno(&mut self, aid: u32, epno: &str) -> Result<Option<Episode>> {
+        let cache = sqlx::query!("SELECT json FROM episodes WHERE aid = $1", aid)
+            .fetch_all(crate::DB.get().await)
+            .await?;
+
+        for row in cache {
+            let episode: Episode =
+                serde_json::from_str(&row.json).context("Invalid record in database")?;
+
+            if episode.has_epno(epno) {
+                return Ok(Some(episode));
+            }
+        }
+
+        self.session().await?.episode_by_epno(aid, epno).await
+    }
+
     pub async fn group_by_gid(&mut self, gid: u32) -> Result<Option<records::Group>> {
         let cache = sqlx::query!("SELECT json FROM groups WHERE gid = $1", gid)
             .fetch_optional(crate::DB.get().await)
@@ -116,4 +329,164 @@ impl Anidb {
 
         self.session().await?.group_by_gid(gid).await
     }
+
+    pub async fn mylist_by_fid(&mut self, fid: u32) -> Result<Option<MylistEntry>> {
+        let cache = sqlx::query!("SELECT json, fetched FROM mylist WHERE fid = $1", fid)
+            .fetch_optional(crate::DB.get().await)
+            .await?;
+
+        if let Some(entry) = cache {
+            let fetched = DateTime::from_timestamp(entry.fetched, 0).unwrap_or_default();
+
+            if Utc::now() - fetched < MYLIST_MAX_AGE {
+                return parse_cached(&entry.json).map(Some);
+            }
+        }
+
+        self.fetch_mylist_by_fid(fid).await
+    }
+
+    /// Ask AniDB even if the entry is cached, for changes made outside of tetsu
+    pub async fn fetch_mylist_by_fid(&mut self, fid: u32) -> Result<Option<MylistEntry>> {
+        self.session().await?.mylist_by_fid(fid).await
+    }
+
+    pub async fn mylist_add(&mut self, fid: u32, update: &MylistUpdate) -> Result<MylistAdd> {
+        self.session().await?.mylist_add(fid, update).await
+    }
+
+    /// Returns false if there is no such entry
+    pub async fn mylist_edit(&mut self, lid: u32, update: &MylistUpdate) -> Result<bool> {
+        self.session().await?.mylist_edit(lid, update).await
+    }
+
+    /// Returns false if the file isn't in the mylist
+    pub async fn mylist_edit_by_fid(&mut self, fid: u32, update: &MylistUpdate) -> Result<bool> {
+        self.session().await?.mylist_edit_by_fid(fid, update).await
+    }
+
+    /// Returns false if there is no such entry
+    pub async fn mylist_del(&mut self, lid: u32) -> Result<bool> {
+        self.session().await?.mylist_del(lid).await
+    }
+}
+
+fn parse_cached<T: serde::de::DeserializeOwned>(json: &str) -> Result<T> {
+    serde_json::from_str(json).context("Invalid record in database")
+}
+
+/// A freshly fetched record, or the partial one from the cache if fetching it again failed
+fn or_partial<T: serde::de::DeserializeOwned>(
+    fetched: Result<Option<T>>,
+    cached: Option<String>,
+) -> Result<Option<T>> {
+    match (fetched, cached) {
+        (Err(e), Some(json)) => {
+            log::warn!("Using a partial record from the cache: {e:#}");
+            parse_cached(&json).map(Some)
+        }
+        (fetched, _) => fetched,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        mock::{fixtures, MockServer},
+        *,
+    };
+
+    const ED2K: &str = "0123456789abcdef0123456789abcdef";
+
+    #[tokio::test]
+    async fn file_by_ed2k() {
+        let server = MockServer::logged_in().await;
+        server.add_file(&fixtures::file(100, 1234, ED2K)).unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        let file = anidb.file_by_ed2k(1234, ED2K).await.unwrap().unwrap();
+        assert_eq!(file.fid, 100);
+        assert_eq!(file.aid, 1);
+
+        assert!(anidb.file_by_ed2k(4321, ED2K).await.unwrap().is_none());
+
+        // known files are served from the database from now on
+        let sent = server.requests().len();
+        let cached = anidb.file_by_ed2k(1234, ED2K).await.unwrap();
+        assert_eq!(cached, Some(file));
+        assert_eq!(server.requests().len(), sent);
+    }
+
+    #[tokio::test]
+    async fn anime_description() {
+        let server = MockServer::logged_in().await;
+        let description = format!("{}|{}", "a".repeat(1500), "b".repeat(600));
+        server.add_description(2, &description);
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        let fetched = anidb.anime_description(2).await.unwrap();
+        assert_eq!(fetched.as_ref(), Some(&description));
+
+        let sent = server.requests().len();
+        assert_eq!(anidb.anime_description(2).await.unwrap(), fetched);
+        assert_eq!(server.requests().len(), sent);
+
+        assert_eq!(anidb.anime_description(3).await.unwrap(), None);
+
+        let sent = server.requests().len();
+        assert_eq!(anidb.anime_description(3).await.unwrap(), None);
+        assert_eq!(server.requests().len(), sent);
+    }
+
+    #[tokio::test]
+    async fn character_and_creator() {
+        let server = MockServer::logged_in().await;
+        server.add_character(fixtures::CHARACTER).unwrap();
+        server.add_creator(fixtures::CREATOR).unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        let character = anidb.character_by_id(1).await.unwrap().unwrap();
+        assert_eq!(character.name, "Lafiel");
+        assert_eq!(character.anime[0].creatorid, Some(1));
+
+        let creator = anidb.creator_by_id(1).await.unwrap().unwrap();
+        assert_eq!(creator.name, "Kawasumi Ayako");
+
+        assert!(anidb.character_by_id(2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn mylist_add_and_edit() {
+        let server = MockServer::logged_in().await;
+        server.add_file(&fixtures::file(101, 5678, ED2K)).unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        let update = MylistUpdate {
+            state: Some(MylistState::Hdd),
+            ..Default::default()
+        };
+
+        let MylistAdd::Added(entry) = anidb.mylist_add(101, &update).await.unwrap() else {
+            panic!("file should have been added");
+        };
+        assert_eq!(entry.state, MylistState::Hdd);
+        assert!(!entry.viewed());
+
+        let res = anidb.mylist_add(101, &update).await.unwrap();
+        assert_eq!(res, MylistAdd::AlreadyInMylist(entry.clone()));
+
+        let watched = MylistUpdate {
+            viewed: Some(true),
+            ..Default::default()
+        };
+        assert!(anidb.mylist_edit(entry.lid, &watched).await.unwrap());
+        assert!(server.mylist()[0].viewed());
+
+        let cached = anidb.mylist_by_fid(101).await.unwrap().unwrap();
+        assert!(cached.viewed());
+    }
 }
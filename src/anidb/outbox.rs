@@ -0,0 +1,184 @@
+//! Mylist updates that have to reach AniDB eventually. Updates are written to the
+//! `mylist_outbox` table first and retried with backoff until AniDB accepts them,
+//! so that flood protection or a ban never loses a watched episode. Sent updates are
+//! kept as done, which stops the same file from being queued again.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::{sync::RwLock, time::sleep};
+
+use super::{
+    records::{MylistEntry, MylistState},
+    Anidb, MylistAdd, MylistUpdate,
+};
+
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+/// Queue marking a file as watched, unless we already know it is or it has been queued
+/// before
+pub async fn queue_watched(fid: u32, viewdate: DateTime<Utc>) -> Result<()> {
+    let db = crate::DB.get().await;
+
+    let cached = sqlx::query!("SELECT json FROM mylist WHERE fid = $1", fid)
+        .fetch_optional(db)
+        .await?
+        .map(|row| serde_json::from_str::<MylistEntry>(&row.json))
+        .transpose()
+        .context("Invalid record in database")?;
+
+    if cached.is_some_and(|entry| entry.viewed()) {
+        return Ok(());
+    }
+
+    let viewdate = viewdate.timestamp();
+
+    sqlx::query!(
+        "INSERT INTO mylist_outbox (fid, viewdate, next_attempt)
+        VALUES ($1, $2, $2)
+        ON CONFLICT (fid) DO NOTHING",
+        fid,
+        viewdate,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Keep sending queued updates in the background
+pub async fn run(anidb: Arc<RwLock<Anidb>>) {
+    loop {
+        if let Err(e) = flush(&mut *anidb.write().await).await {
+            log::error!("Failed to process mylist outbox: {}", e);
+        }
+
+        sleep(Duration::from_secs(10)).await;
+    }
+}
+
+/// Send every update that is due, rescheduling the ones that fail
+pub async fn flush(anidb: &mut Anidb) -> Result<()> {
+    let db = crate::DB.get().await;
+    let now = Utc::now().timestamp();
+
+    let due = sqlx::query!(
+        "SELECT fid, viewdate, attempts FROM mylist_outbox
+        WHERE done = 0 AND next_attempt <= $1
+        ORDER BY next_attempt",
+        now,
+    )
+    .fetch_all(db)
+    .await?;
+
+    for row in due {
+        let fid = row.fid as u32;
+        let viewdate = DateTime::from_timestamp(row.viewdate, 0).unwrap_or_else(Utc::now);
+
+        match mark_watched(anidb, fid, viewdate).await {
+            Ok(()) => {
+                sqlx::query!("UPDATE mylist_outbox SET done = 1 WHERE fid = $1", fid)
+                    .execute(db)
+                    .await?;
+            }
+            Err(e) => {
+                log::warn!("Failed to mark file {} as watched, will retry: {:#}", fid, e);
+
+                let attempts = row.attempts + 1;
+                let backoff = (60 << attempts.min(16)).min(MAX_BACKOFF_SECS);
+                let next_attempt = Utc::now().timestamp() + backoff;
+                let error = format!("{e:#}");
+
+                sqlx::query!(
+                    "UPDATE mylist_outbox
+                    SET attempts = $1, last_error = $2, next_attempt = $3
+                    WHERE fid = $4",
+                    attempts,
+                    error,
+                    next_attempt,
+                    fid,
+                )
+                .execute(db)
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn mark_watched(anidb: &mut Anidb, fid: u32, viewdate: DateTime<Utc>) -> Result<()> {
+    let watched = MylistUpdate {
+        viewed: Some(true),
+        viewdate: Some(viewdate),
+        ..Default::default()
+    };
+
+    if anidb.mylist_edit_by_fid(fid, &watched).await? {
+        // make sure the cache knows, so we don't queue this file again
+        anidb.mylist_by_fid(fid).await?;
+        return Ok(());
+    }
+
+    let add = MylistUpdate {
+        state: Some(MylistState::Hdd),
+        ..watched
+    };
+
+    match anidb.mylist_add(fid, &add).await? {
+        MylistAdd::Added(_) | MylistAdd::AlreadyInMylist(_) => Ok(()),
+        MylistAdd::NoSuchFile => {
+            log::warn!("File {} is not known to AniDB, dropping watched update", fid);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::mock::{fixtures, MockServer};
+
+    #[tokio::test]
+    async fn marks_queued_files_watched() {
+        let server = MockServer::logged_in().await;
+        server
+            .add_file(&fixtures::file(300, 42, "00000000000000000000000000000300"))
+            .unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        queue_watched(300, Utc::now()).await.unwrap();
+        flush(&mut anidb).await.unwrap();
+
+        let mylist = server.mylist();
+        assert_eq!(mylist.len(), 1);
+        assert!(mylist[0].viewed());
+
+        let queued = || async {
+            sqlx::query_scalar!("SELECT COUNT(*) FROM mylist_outbox WHERE done = 0")
+                .fetch_one(crate::DB.get().await)
+                .await
+                .unwrap()
+        };
+        assert_eq!(queued().await, 0);
+
+        // already known to be watched, so nothing gets queued
+        queue_watched(300, Utc::now()).await.unwrap();
+        assert_eq!(queued().await, 0);
+
+        // AniDB doesn't know this one, and isn't asked again on every progress report
+        let requests = || server.requests().len();
+        queue_watched(310, Utc::now()).await.unwrap();
+        flush(&mut anidb).await.unwrap();
+        let sent = requests();
+
+        queue_watched(310, Utc::now()).await.unwrap();
+        queue_watched(310, Utc::now()).await.unwrap();
+        assert_eq!(queued().await, 0);
+
+        flush(&mut anidb).await.unwrap();
+        assert_eq!(requests(), sent);
+    }
+}
@@ -2,9 +2,10 @@ use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::{Record, RecordSplit};
+use super::{Mask, MaskField, MaskedRecord, Record, RecordSplit};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Anime {
     pub aid: u32,
     pub dateflags: i32,
@@ -31,34 +32,142 @@ pub struct Anime {
     pub parody_count: i32,
 }
 
+/// Fields of the ANIME amask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimeField {
+    Aid,
+    Dateflags,
+    Year,
+    Atype,
+    RelatedAidList,
+    RelatedAidType,
+    RomajiName,
+    KanjiName,
+    EnglishName,
+    ShortNameList,
+    Episodes,
+    SpecialEpCount,
+    AirDate,
+    EndDate,
+    Picname,
+    Nsfw,
+    AnnId,
+    CharacteridList,
+    SpecialsCount,
+    CreditsCount,
+    OtherCount,
+    TrailerCount,
+    ParodyCount,
+}
+
+impl MaskField for AnimeField {
+    const BYTES: usize = 7;
+
+    const ALL: &'static [Self] = &[
+        Self::Aid,
+        Self::Dateflags,
+        Self::Year,
+        Self::Atype,
+        Self::RelatedAidList,
+        Self::RelatedAidType,
+        Self::RomajiName,
+        Self::KanjiName,
+        Self::EnglishName,
+        Self::ShortNameList,
+        Self::Episodes,
+        Self::SpecialEpCount,
+        Self::AirDate,
+        Self::EndDate,
+        Self::Picname,
+        Self::Nsfw,
+        Self::AnnId,
+        Self::CharacteridList,
+        Self::SpecialsCount,
+        Self::CreditsCount,
+        Self::OtherCount,
+        Self::TrailerCount,
+        Self::ParodyCount,
+    ];
+
+    const EXPENDABLE: &'static [Self] = &[
+        Self::CharacteridList,
+        Self::ShortNameList,
+        Self::RelatedAidType,
+        Self::RelatedAidList,
+        Self::KanjiName,
+        Self::EnglishName,
+        Self::Picname,
+    ];
+
+    fn position(self) -> (usize, u8) {
+        match self {
+            Self::Aid => (0, 7),
+            Self::Dateflags => (0, 6),
+            Self::Year => (0, 5),
+            Self::Atype => (0, 4),
+            Self::RelatedAidList => (0, 3),
+            Self::RelatedAidType => (0, 2),
+            Self::RomajiName => (1, 7),
+            Self::KanjiName => (1, 6),
+            Self::EnglishName => (1, 5),
+            Self::ShortNameList => (1, 3),
+            Self::Episodes => (2, 7),
+            Self::SpecialEpCount => (2, 5),
+            Self::AirDate => (2, 4),
+            Self::EndDate => (2, 3),
+            Self::Picname => (2, 1),
+            Self::Nsfw => (3, 0),
+            Self::AnnId => (4, 6),
+            Self::CharacteridList => (5, 7),
+            Self::SpecialsCount => (6, 7),
+            Self::CreditsCount => (6, 6),
+            Self::OtherCount => (6, 5),
+            Self::TrailerCount => (6, 4),
+            Self::ParodyCount => (6, 3),
+        }
+    }
+}
+
+impl MaskedRecord for Anime {
+    type Field = AnimeField;
+
+    fn default_mask() -> Mask<AnimeField> {
+        Mask::all()
+    }
+
+    fn take_field(&mut self, field: AnimeField, fields: &mut RecordSplit) -> Result<()> {
+        match field {
+            AnimeField::Aid => self.aid = fields.take_parsed()?,
+            AnimeField::Dateflags => self.dateflags = fields.take_parsed()?,
+            AnimeField::Year => self.year = fields.take_string()?,
+            AnimeField::Atype => self.atype = fields.take_string()?,
+            AnimeField::RelatedAidList => self.related_aid_list = fields.take_separated('\'')?,
+            AnimeField::RelatedAidType => self.related_aid_type = fields.take_separated('\'')?,
+            AnimeField::RomajiName => self.romaji_name = fields.take_string()?,
+            AnimeField::KanjiName => self.kanji_name = fields.take_string()?,
+            AnimeField::EnglishName => self.english_name = fields.take_string()?,
+            AnimeField::ShortNameList => self.short_name_list = fields.take_separated('\'')?,
+            AnimeField::Episodes => self.episodes = fields.take_parsed()?,
+            AnimeField::SpecialEpCount => self.special_ep_count = fields.take_parsed()?,
+            AnimeField::AirDate => self.air_date = fields.take_timestamp()?,
+            AnimeField::EndDate => self.end_date = fields.take_timestamp()?,
+            AnimeField::Picname => self.picname = fields.take_string()?,
+            AnimeField::Nsfw => self.nsfw = fields.take_bool()?,
+            AnimeField::AnnId => self.ann_id = fields.take_parsed()?,
+            AnimeField::CharacteridList => self.characterid_list = fields.take_separated(',')?,
+            AnimeField::SpecialsCount => self.specials_count = fields.take_parsed()?,
+            AnimeField::CreditsCount => self.credits_count = fields.take_parsed()?,
+            AnimeField::OtherCount => self.other_count = fields.take_parsed()?,
+            AnimeField::TrailerCount => self.trailer_count = fields.take_parsed()?,
+            AnimeField::ParodyCount => self.parody_count = fields.take_parsed()?,
+        }
+
+        Ok(())
+    }
+}
+
 impl Record for Anime {
     fn parse(input: &str) -> Result<Self> {
-        let mut fields = RecordSplit::new(input);
-
-        Ok(Self {
-            aid: fields.take_parsed()?,
-            dateflags: fields.take_parsed()?,
-            year: fields.take_string()?,
-            atype: fields.take_string()?,
-            related_aid_list: fields.take_separated('\'')?,
-            related_aid_type: fields.take_separated('\'')?,
-            romaji_name: fields.take_string()?,
-            kanji_name: fields.take_string()?,
-            english_name: fields.take_string()?,
-            short_name_list: fields.take_separated('\'')?,
-            episodes: fields.take_parsed()?,
-            special_ep_count: fields.take_parsed()?,
-            air_date: fields.take_timestamp()?,
-            end_date: fields.take_timestamp()?,
-            picname: fields.take_string()?,
-            nsfw: fields.take_bool()?,
-            ann_id: fields.take_parsed()?,
-            characterid_list: fields.take_separated(',')?,
-            specials_count: fields.take_parsed()?,
-            credits_count: fields.take_parsed()?,
-            other_count: fields.take_parsed()?,
-            trailer_count: fields.take_parsed()?,
-            parody_count: fields.take_parsed()?,
-        })
+        Self::parse_masked(input, &Self::default_mask())
     }
 }
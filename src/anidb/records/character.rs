@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{Record, RecordSplit};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Character {
+    pub charid: u32,
+    pub kanji_name: String,
+    pub name: String,
+    pub picname: String,
+    pub anime: Vec<Appearance>,
+    pub episode_list: Vec<u32>,
+    pub updated: DateTime<Utc>,
+    pub ctype: i16,
+    pub gender: String,
+}
+
+/// How a character appears in one anime, and who voices them there
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Appearance {
+    pub aid: u32,
+    pub appearance: i16,
+    pub creatorid: Option<u32>,
+    pub main_seiyuu: bool,
+}
+
+impl Appearance {
+    // aid,appearance,creatorid,is_main_seiyuu
+    fn parse(input: &str) -> Result<Self> {
+        let mut parts = input.split(',');
+        let mut next = || parts.next().context("Unexpected end of anime block");
+
+        Ok(Self {
+            aid: next()?.parse()?,
+            appearance: next()?.parse()?,
+            creatorid: next()?.parse().ok().filter(|&id| id != 0),
+            main_seiyuu: next()? == "1",
+        })
+    }
+}
+
+impl Record for Character {
+    fn parse(input: &str) -> Result<Self> {
+        let mut fields = RecordSplit::new(input);
+
+        Ok(Self {
+            charid: fields.take_parsed()?,
+            kanji_name: fields.take_string()?,
+            name: fields.take_string()?,
+            picname: fields.take_string()?,
+            anime: fields
+                .take_separated::<String>('\'')?
+                .iter()
+                .map(|block| Appearance::parse(block))
+                .collect::<Result<_>>()?,
+            episode_list: fields.take_separated(',')?,
+            updated: fields.take_timestamp()?,
+            ctype: fields.take_parsed()?,
+            gender: fields.take_string()?,
+        })
+    }
+}
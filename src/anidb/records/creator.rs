@@ -0,0 +1,38 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{Record, RecordSplit};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Creator {
+    pub creatorid: u32,
+    pub kanji_name: String,
+    pub name: String,
+    pub ctype: i32,
+    pub picname: String,
+    pub url_english: String,
+    pub url_japanese: String,
+    pub wiki_url_english: String,
+    pub wiki_url_japanese: String,
+    pub updated: DateTime<Utc>,
+}
+
+impl Record for Creator {
+    fn parse(input: &str) -> Result<Self> {
+        let mut fields = RecordSplit::new(input);
+
+        Ok(Self {
+            creatorid: fields.take_parsed()?,
+            kanji_name: fields.take_string()?,
+            name: fields.take_string()?,
+            ctype: fields.take_parsed()?,
+            picname: fields.take_string()?,
+            url_english: fields.take_string()?,
+            url_japanese: fields.take_string()?,
+            wiki_url_english: fields.take_string()?,
+            wiki_url_japanese: fields.take_string()?,
+            updated: fields.take_timestamp()?,
+        })
+    }
+}
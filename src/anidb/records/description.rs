@@ -0,0 +1,23 @@
+use anyhow::Result;
+
+use super::{Record, RecordSplit};
+
+/// One part of an ANIMEDESC response. Long descriptions are split over several parts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptionPart {
+    pub part: u32,
+    pub max_parts: u32,
+    pub text: String,
+}
+
+impl Record for DescriptionPart {
+    fn parse(input: &str) -> Result<Self> {
+        let mut fields = RecordSplit::new(input);
+
+        Ok(Self {
+            part: fields.take_parsed()?,
+            max_parts: fields.take_parsed()?,
+            text: fields.take_rest(),
+        })
+    }
+}
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 
 use super::{Record, RecordSplit};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Episode {
     pub eid: u32,
     pub aid: u32,
@@ -38,3 +38,21 @@ impl Record for Episode {
         })
     }
 }
+
+impl Episode {
+    /// Whether this is episode `epno`, ignoring the zero padding AniDB uses in some places
+    /// and not in others
+    pub fn has_epno(&self, epno: &str) -> bool {
+        unpadded(&self.epno) == unpadded(epno)
+    }
+}
+
+fn unpadded(epno: &str) -> String {
+    let number = epno.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let prefix = epno[..epno.len() - number.len()].to_ascii_uppercase();
+
+    match number.parse::<u32>() {
+        Ok(number) => format!("{prefix}{number}"),
+        Err(_) => epno.to_string(),
+    }
+}
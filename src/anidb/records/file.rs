@@ -2,9 +2,10 @@ use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::{Record, RecordSplit};
+use super::{Mask, MaskField, MaskedRecord, Record, RecordSplit};
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct File {
     pub fid: u32,
     pub aid: u32,
@@ -13,6 +14,9 @@ pub struct File {
     pub state: i16,
     pub size: i64,
     pub ed2k: String,
+    pub md5: String,
+    pub sha1: String,
+    pub crc32: String,
     pub colour_depth: String,
     pub quality: String,
     pub source: String,
@@ -28,31 +32,149 @@ pub struct File {
     pub aired_date: DateTime<Utc>,
 }
 
+/// Fields of the FILE fmask. The fid always comes first and isn't part of the mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileField {
+    Aid,
+    Eid,
+    Gid,
+    State,
+    Size,
+    Ed2k,
+    Md5,
+    Sha1,
+    Crc32,
+    ColourDepth,
+    Quality,
+    Source,
+    AudioCodecList,
+    AudioBitrateList,
+    VideoCodec,
+    VideoBitrate,
+    VideoResolution,
+    DubLanguage,
+    SubLanguage,
+    LengthInSeconds,
+    Description,
+    AiredDate,
+}
+
+impl MaskField for FileField {
+    const BYTES: usize = 5;
+
+    const ALL: &'static [Self] = &[
+        Self::Aid,
+        Self::Eid,
+        Self::Gid,
+        Self::State,
+        Self::Size,
+        Self::Ed2k,
+        Self::Md5,
+        Self::Sha1,
+        Self::Crc32,
+        Self::ColourDepth,
+        Self::Quality,
+        Self::Source,
+        Self::AudioCodecList,
+        Self::AudioBitrateList,
+        Self::VideoCodec,
+        Self::VideoBitrate,
+        Self::VideoResolution,
+        Self::DubLanguage,
+        Self::SubLanguage,
+        Self::LengthInSeconds,
+        Self::Description,
+        Self::AiredDate,
+    ];
+
+    const EXPENDABLE: &'static [Self] = &[
+        Self::Description,
+        Self::SubLanguage,
+        Self::DubLanguage,
+        Self::AudioCodecList,
+        Self::AudioBitrateList,
+        Self::VideoResolution,
+        Self::VideoBitrate,
+        Self::VideoCodec,
+        Self::Source,
+        Self::Quality,
+        Self::ColourDepth,
+        Self::Sha1,
+        Self::Md5,
+    ];
+
+    fn position(self) -> (usize, u8) {
+        match self {
+            Self::Aid => (0, 6),
+            Self::Eid => (0, 5),
+            Self::Gid => (0, 4),
+            Self::State => (0, 0),
+            Self::Size => (1, 7),
+            Self::Ed2k => (1, 6),
+            Self::Md5 => (1, 5),
+            Self::Sha1 => (1, 4),
+            Self::Crc32 => (1, 3),
+            Self::ColourDepth => (1, 1),
+            Self::Quality => (2, 7),
+            Self::Source => (2, 6),
+            Self::AudioCodecList => (2, 5),
+            Self::AudioBitrateList => (2, 4),
+            Self::VideoCodec => (2, 3),
+            Self::VideoBitrate => (2, 2),
+            Self::VideoResolution => (2, 1),
+            Self::DubLanguage => (3, 7),
+            Self::SubLanguage => (3, 6),
+            Self::LengthInSeconds => (3, 5),
+            Self::Description => (3, 4),
+            Self::AiredDate => (3, 3),
+        }
+    }
+}
+
+impl MaskedRecord for File {
+    type Field = FileField;
+
+    fn default_mask() -> Mask<FileField> {
+        Mask::all()
+    }
+
+    fn take_fixed(&mut self, fields: &mut RecordSplit) -> Result<()> {
+        self.fid = fields.take_parsed()?;
+        Ok(())
+    }
+
+    fn take_field(&mut self, field: FileField, fields: &mut RecordSplit) -> Result<()> {
+        match field {
+            FileField::Aid => self.aid = fields.take_parsed()?,
+            FileField::Eid => self.eid = fields.take_parsed()?,
+            FileField::Gid => self.gid = fields.take_parsed()?,
+            FileField::State => self.state = fields.take_parsed()?,
+            FileField::Size => self.size = fields.take_parsed()?,
+            FileField::Ed2k => self.ed2k = fields.take_string()?,
+            FileField::Md5 => self.md5 = fields.take_string()?,
+            FileField::Sha1 => self.sha1 = fields.take_string()?,
+            FileField::Crc32 => self.crc32 = fields.take_string()?,
+            FileField::ColourDepth => self.colour_depth = fields.take_string()?,
+            FileField::Quality => self.quality = fields.take_string()?,
+            FileField::Source => self.source = fields.take_string()?,
+            FileField::AudioCodecList => self.audio_codec_list = fields.take_separated('\'')?,
+            FileField::AudioBitrateList => self.audio_bitrate_list = fields.take_separated('\'')?,
+            FileField::VideoCodec => self.video_codec = fields.take_separated('\'')?,
+            FileField::VideoBitrate => self.video_bitrate = fields.take_separated('\'')?,
+            FileField::VideoResolution => self.video_resolution = fields.take_separated('\'')?,
+            FileField::DubLanguage => self.dub_language = fields.take_string()?,
+            FileField::SubLanguage => self.sub_language = fields.take_string()?,
+            FileField::LengthInSeconds => self.length_in_seconds = fields.take_parsed()?,
+            FileField::Description => self.description = fields.take_string()?,
+            FileField::AiredDate => self.aired_date = fields.take_timestamp()?,
+        }
+
+        Ok(())
+    }
+}
+
 impl Record for File {
     fn parse(input: &str) -> Result<Self> {
-        let mut fields = RecordSplit::new(input);
-
-        Ok(Self {
-            fid: fields.take_parsed()?,
-            aid: fields.take_parsed()?,
-            eid: fields.take_parsed()?,
-            gid: fields.take_parsed()?,
-            state: fields.take_parsed()?,
-            size: fields.take_parsed()?,
-            ed2k: fields.take_string()?,
-            colour_depth: fields.take_string()?,
-            quality: fields.take_string()?,
-            source: fields.take_string()?,
-            audio_codec_list: fields.take_separated('\'')?,
-            audio_bitrate_list: fields.take_separated('\'')?,
-            video_codec: fields.take_separated('\'')?,
-            video_bitrate: fields.take_separated('\'')?,
-            video_resolution: fields.take_separated('\'')?,
-            dub_language: fields.take_string()?,
-            sub_language: fields.take_string()?,
-            length_in_seconds: fields.take_parsed()?,
-            description: fields.take_string()?,
-            aired_date: fields.take_timestamp()?,
-        })
+        Self::parse_masked(input, &Self::default_mask())
     }
 }
@@ -0,0 +1,184 @@
+use std::{
+    fmt::{Debug, Write},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::RecordSplit;
+use crate::anidb::command_builder::CmdArgument;
+
+/// A field that can be requested through a bitmask such as FILE's fmask or ANIME's amask
+pub trait MaskField: Copy + PartialEq + Debug + 'static {
+    /// Length of the mask in bytes
+    const BYTES: usize;
+    /// Every field we know how to parse
+    const ALL: &'static [Self];
+    /// Fields that can be left out when a response doesn't fit in a packet, most expendable first
+    const EXPENDABLE: &'static [Self];
+
+    /// Byte and bit of the field, bit 7 being the most significant one
+    fn position(self) -> (usize, u8);
+}
+
+/// The set of fields to request. AniDB returns them in mask order, which is also the order
+/// [`Mask::fields`] yields them in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask<F> {
+    fields: Vec<F>,
+}
+
+impl<F: MaskField> Mask<F> {
+    pub fn new() -> Self {
+        Self { fields: vec![] }
+    }
+
+    pub fn all() -> Self {
+        F::ALL
+            .iter()
+            .fold(Self::new(), |mask, &field| mask.with(field))
+    }
+
+    pub fn with(mut self, field: F) -> Self {
+        if !self.contains(field) {
+            self.fields.push(field);
+            self.fields.sort_by_key(|f| {
+                let (byte, bit) = f.position();
+                (byte, 7 - bit)
+            });
+        }
+
+        self
+    }
+
+    pub fn without(mut self, field: F) -> Self {
+        self.fields.retain(|&f| f != field);
+        self
+    }
+
+    pub fn contains(&self, field: F) -> bool {
+        self.fields.contains(&field)
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = F> + '_ {
+        self.fields.iter().copied()
+    }
+
+    /// The same mask minus its most expendable field, if there is one left to drop
+    pub fn shrink(&self) -> Option<(Self, F)> {
+        F::EXPENDABLE
+            .iter()
+            .find(|&&field| self.contains(field))
+            .map(|&field| (self.clone().without(field), field))
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; F::BYTES];
+
+        for field in self.fields() {
+            let (byte, bit) = field.position();
+            bytes[byte] |= 1 << bit;
+        }
+
+        bytes
+    }
+}
+
+impl<F: MaskField> Default for Mask<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: MaskField> FromStr for Mask<F> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != F::BYTES * 2 {
+            bail!("Expected a mask of {} bytes", F::BYTES);
+        }
+
+        let bytes = (0..F::BYTES)
+            .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid mask")?;
+
+        Ok(F::ALL
+            .iter()
+            .filter(|field| {
+                let (byte, bit) = field.position();
+                bytes[byte] & (1 << bit) != 0
+            })
+            .fold(Self::new(), |mask, &field| mask.with(field)))
+    }
+}
+
+impl<F: MaskField> CmdArgument for &Mask<F> {
+    type Output = String;
+
+    fn escaped(&self) -> String {
+        let mut out = String::new();
+
+        for byte in self.bytes() {
+            write!(out, "{byte:02x}").unwrap();
+        }
+
+        out
+    }
+}
+
+/// A record whose fields are chosen with a mask. The parser follows the mask, so fields
+/// that weren't requested keep their default value.
+pub(crate) trait MaskedRecord: Default {
+    type Field: MaskField;
+
+    /// What we ask for unless told otherwise
+    fn default_mask() -> Mask<Self::Field>;
+
+    /// Fields that come before the masked ones no matter the mask
+    fn take_fixed(&mut self, _fields: &mut RecordSplit) -> Result<()> {
+        Ok(())
+    }
+
+    fn take_field(&mut self, field: Self::Field, fields: &mut RecordSplit) -> Result<()>;
+
+    fn parse_masked(input: &str, mask: &Mask<Self::Field>) -> Result<Self> {
+        let mut fields = RecordSplit::new(input);
+        let mut record = Self::default();
+
+        record.take_fixed(&mut fields)?;
+
+        for field in mask.fields() {
+            record.take_field(field, &mut fields)?;
+        }
+
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::records::{Anime, AnimeField, File, FileField};
+
+    #[test]
+    fn default_masks() {
+        assert_eq!((&File::default_mask()).escaped(), "71fafef800");
+        assert_eq!((&Anime::default_mask()).escaped(), "fce8ba014080f8");
+        assert_eq!("71fafef800".parse::<Mask<FileField>>().unwrap(), File::default_mask());
+    }
+
+    #[test]
+    fn field_order() {
+        let mask = Mask::new()
+            .with(AnimeField::RomajiName)
+            .with(AnimeField::Aid)
+            .with(AnimeField::Year);
+
+        assert_eq!(
+            mask.fields().collect::<Vec<_>>(),
+            vec![AnimeField::Aid, AnimeField::Year, AnimeField::RomajiName]
+        );
+        assert_eq!((&mask).escaped(), "a0800000000000");
+    }
+}
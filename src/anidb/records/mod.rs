@@ -7,20 +7,31 @@ use anyhow::{bail, Context, Result};
 use chrono::TimeZone;
 
 mod anime;
+mod character;
+mod creator;
+mod description;
 mod episode;
 mod file;
 mod group;
+mod mask;
+mod mylist;
 
-pub use anime::Anime;
+pub use anime::{Anime, AnimeField};
+pub use character::{Appearance, Character};
+pub use creator::Creator;
+pub use description::DescriptionPart;
 pub use episode::Episode;
-pub use file::File;
+pub use file::{File, FileField};
 pub use group::Group;
+pub(crate) use mask::MaskedRecord;
+pub use mask::{Mask, MaskField};
+pub use mylist::{MylistEntry, MylistState};
 
 pub trait Record: Sized {
     fn parse(input: &str) -> Result<Self>;
 }
 
-struct RecordSplit<'a> {
+pub(crate) struct RecordSplit<'a> {
     input: Split<'a, char>,
 }
 
@@ -63,6 +74,11 @@ impl<'a> RecordSplit<'a> {
             .context("Timestamp out of range")
     }
 
+    /// Everything that's left, for free text that may contain the separator
+    pub fn take_rest(&mut self) -> String {
+        unescape(&self.input.by_ref().collect::<Vec<_>>().join("|"))
+    }
+
     pub fn take_separated<T>(&mut self, sep: char) -> Result<Vec<T>>
     where
         T: FromStr,
@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+use super::{Record, RecordSplit};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MylistEntry {
+    pub lid: u32,
+    pub fid: u32,
+    pub eid: u32,
+    pub aid: u32,
+    pub gid: u32,
+    pub date: DateTime<Utc>,
+    pub state: MylistState,
+    pub viewdate: DateTime<Utc>,
+    pub storage: String,
+    pub source: String,
+    pub other: String,
+    pub filestate: i16,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive, Serialize_repr, Deserialize_repr,
+)]
+#[repr(u8)]
+pub enum MylistState {
+    Unknown = 0,
+    Hdd = 1,
+    Cd = 2,
+    Deleted = 3,
+    Remote = 4,
+}
+
+impl MylistEntry {
+    /// AniDB reports a viewdate of 0 for entries that haven't been watched
+    pub fn viewed(&self) -> bool {
+        self.viewdate.timestamp() != 0
+    }
+}
+
+impl Record for MylistEntry {
+    fn parse(input: &str) -> Result<Self> {
+        let mut fields = RecordSplit::new(input);
+
+        Ok(Self {
+            lid: fields.take_parsed()?,
+            fid: fields.take_parsed()?,
+            eid: fields.take_parsed()?,
+            aid: fields.take_parsed()?,
+            gid: fields.take_parsed()?,
+            date: fields.take_timestamp()?,
+            state: MylistState::from_u8(fields.take_parsed()?).context("Unknown mylist state")?,
+            viewdate: fields.take_timestamp()?,
+            storage: fields.take_string()?,
+            source: fields.take_string()?,
+            other: fields.take_string()?,
+            filestate: fields.take_parsed()?,
+        })
+    }
+}
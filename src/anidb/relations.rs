@@ -0,0 +1,195 @@
+//! The graph of related anime. Relations come from the anime records themselves, so the
+//! graph is built from the cache and only fetches the titles it hasn't seen yet.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::Result;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use serde::Serialize;
+
+use super::{records::Anime, Anidb};
+
+/// Upper bound on how many anime a single graph will pull in
+const MAX_ANIME: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationType {
+    Sequel = 1,
+    Prequel = 2,
+    SameSetting = 11,
+    AlternativeSetting = 12,
+    AlternativeVersion = 32,
+    MusicVideo = 41,
+    Character = 42,
+    SideStory = 51,
+    ParentStory = 52,
+    Summary = 61,
+    FullStory = 62,
+    Other = 100,
+}
+
+impl RelationType {
+    fn parse(s: &str) -> Self {
+        s.parse()
+            .ok()
+            .and_then(Self::from_u32)
+            .unwrap_or(Self::Other)
+    }
+}
+
+impl Anime {
+    /// Related anime ids, paired with how they relate to this one
+    pub fn relations(&self) -> impl Iterator<Item = (u32, RelationType)> + '_ {
+        self.related_aid_list
+            .iter()
+            .copied()
+            .zip(self.related_aid_type.iter().map(|t| RelationType::parse(t)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Related {
+    pub relation: RelationType,
+    #[serde(flatten)]
+    pub anime: Anime,
+}
+
+pub struct RelationGraph {
+    root: u32,
+    anime: HashMap<u32, Anime>,
+}
+
+impl RelationGraph {
+    /// Load `aid` and everything directly related to it. Beyond that, only relations
+    /// accepted by `follow` are walked.
+    pub async fn load(
+        anidb: &mut Anidb,
+        aid: u32,
+        follow: impl Fn(RelationType) -> bool,
+    ) -> Result<Self> {
+        let mut anime = HashMap::new();
+        let mut seen = HashSet::from([aid]);
+        let mut queue = VecDeque::from([aid]);
+
+        while let Some(aid) = queue.pop_front() {
+            if anime.len() >= MAX_ANIME {
+                log::warn!("Relation graph too large, stopping at {} anime", MAX_ANIME);
+                break;
+            }
+
+            let Some(current) = anidb.anime_by_aid(aid).await? else {
+                continue;
+            };
+
+            let is_root = anime.is_empty();
+
+            for (related, relation) in current.relations() {
+                if (is_root || follow(relation)) && seen.insert(related) {
+                    queue.push_back(related);
+                }
+            }
+
+            anime.insert(aid, current);
+        }
+
+        Ok(Self { root: aid, anime })
+    }
+
+    pub fn get(&self, aid: u32) -> Option<&Anime> {
+        self.anime.get(&aid)
+    }
+
+    /// Anime directly related to `aid` that are part of the graph
+    pub fn related(&self, aid: u32) -> Vec<Related> {
+        let Some(anime) = self.get(aid) else {
+            return vec![];
+        };
+
+        anime
+            .relations()
+            .filter_map(|(related, relation)| {
+                Some(Related {
+                    relation,
+                    anime: self.get(related)?.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The root anime with its prequels before it and sequels after it
+    pub fn watch_order(&self) -> Vec<&Anime> {
+        let mut order = VecDeque::new();
+        let mut seen = HashSet::new();
+
+        let Some(root) = self.get(self.root) else {
+            return vec![];
+        };
+
+        order.push_back(root);
+        seen.insert(root.aid);
+
+        let mut first = root;
+        while let Some(prequel) = self.next(first, RelationType::Prequel, &mut seen) {
+            order.push_front(prequel);
+            first = prequel;
+        }
+
+        let mut last = root;
+        while let Some(sequel) = self.next(last, RelationType::Sequel, &mut seen) {
+            order.push_back(sequel);
+            last = sequel;
+        }
+
+        order.into()
+    }
+
+    fn next(
+        &self,
+        anime: &Anime,
+        relation: RelationType,
+        seen: &mut HashSet<u32>,
+    ) -> Option<&Anime> {
+        anime
+            .relations()
+            .filter(|&(_, r)| r == relation)
+            .find_map(|(aid, _)| seen.insert(aid).then(|| self.get(aid)).flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::mock::{fixtures, MockServer};
+
+    /// The fixture anime with different relations
+    fn anime(aid: u32, related: &str, types: &str) -> String {
+        fixtures::ANIME.replacen(
+            "1|0|1999-1999|TV Series|4'6|2'1",
+            &format!("{aid}|0|1999-1999|TV Series|{related}|{types}"),
+            1,
+        )
+    }
+
+    #[tokio::test]
+    async fn watch_order() {
+        let server = MockServer::logged_in().await;
+        server.add_anime(fixtures::ANIME).unwrap();
+        server.add_anime(&anime(4, "1", "1")).unwrap();
+        server.add_anime(&anime(6, "1", "2")).unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        let graph = RelationGraph::load(&mut anidb, 6, |r| r == RelationType::Prequel)
+            .await
+            .unwrap();
+
+        let order: Vec<_> = graph.watch_order().iter().map(|a| a.aid).collect();
+        assert_eq!(order, vec![4, 1, 6]);
+
+        let related = graph.related(1);
+        assert_eq!(related.len(), 2);
+        assert_eq!(related[1].relation, RelationType::Sequel);
+    }
+}
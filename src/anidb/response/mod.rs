@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use anyhow::Context;
 use num_traits::FromPrimitive;
@@ -14,6 +14,31 @@ pub struct Response {
     pub records: Vec<String>,
 }
 
+/// The response filled a whole packet, so AniDB most likely cut it off
+#[derive(Debug)]
+pub struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Response was truncated")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// The response wasn't encrypted with the session's key, so AniDB most likely forgot
+/// about the session
+#[derive(Debug)]
+pub struct Undecryptable;
+
+impl fmt::Display for Undecryptable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to decrypt response")
+    }
+}
+
+impl std::error::Error for Undecryptable {}
+
 impl FromStr for Response {
     type Err = anyhow::Error;
 
@@ -0,0 +1,427 @@
+//! Decides who gets to talk to AniDB and when.
+//!
+//! AniDB allows one packet every two seconds in short bursts, but expects
+//! about one packet every four seconds over longer periods, and bans clients
+//! that don't comply. [`RateLimiter`] tracks both limits with a token bucket
+//! and persists its state, so restarting tetsu doesn't reset the budget.
+//! [`PriorityMutex`] hands out access to the shared session in priority order,
+//! so interactive lookups don't queue up behind bulk indexing.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    ops::{Deref, DerefMut},
+    sync::Mutex as StdMutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Mutex, MutexGuard};
+
+use super::response::codes::ResponseCode;
+use crate::{config::AnidbConfig, db::settings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Indexing, syncing and other long-running work
+    Bulk,
+    Normal,
+    /// Someone is waiting on the result
+    Interactive,
+}
+
+pub struct PriorityMutex<T> {
+    data: Mutex<T>,
+    queue: StdMutex<Queue>,
+}
+
+#[derive(Default)]
+struct Queue {
+    locked: bool,
+    next_seq: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+struct Waiter {
+    priority: Priority,
+    seq: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // highest priority first, then first come first served
+        self.priority
+            .cmp(&other.priority)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl<T> PriorityMutex<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            data: Mutex::new(data),
+            queue: StdMutex::new(Queue::default()),
+        }
+    }
+
+    pub async fn lock(&self, priority: Priority) -> PriorityGuard<'_, T> {
+        let wait = {
+            let mut queue = self.queue.lock().unwrap();
+
+            if queue.locked {
+                let (wake, rx) = oneshot::channel();
+                let seq = queue.next_seq;
+                queue.next_seq += 1;
+                queue.waiting.push(Waiter { priority, seq, wake });
+                Some(rx)
+            } else {
+                queue.locked = true;
+                None
+            }
+        };
+
+        if let Some(rx) = wait {
+            // the previous holder hands the lock over to us directly
+            let mut pending = Pending { mutex: self, rx };
+            let _ = (&mut pending.rx).await;
+        }
+
+        let handoff = Handoff(self);
+        let guard = self.data.lock().await;
+
+        PriorityGuard { guard, _handoff: handoff }
+    }
+
+    fn release(&self) {
+        let mut queue = self.queue.lock().unwrap();
+
+        while let Some(waiter) = queue.waiting.pop() {
+            // fails if the waiter has given up in the meantime
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+        }
+
+        queue.locked = false;
+    }
+}
+
+/// A place in the queue. If it's dropped after being woken up, but before taking the lock,
+/// the lock is passed on instead of staying locked for good.
+struct Pending<'a, T> {
+    mutex: &'a PriorityMutex<T>,
+    rx: oneshot::Receiver<()>,
+}
+
+impl<T> Drop for Pending<'_, T> {
+    fn drop(&mut self) {
+        // after closing, `release` can't hand the lock to us anymore, so either it already
+        // has or it moves on to the next waiter
+        self.rx.close();
+
+        if self.rx.try_recv().is_ok() {
+            self.mutex.release();
+        }
+    }
+}
+
+struct Handoff<'a, T>(&'a PriorityMutex<T>);
+
+impl<T> Drop for Handoff<'_, T> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+pub struct PriorityGuard<'a, T> {
+    // dropped in order: unlock the data, then pass it on
+    guard: MutexGuard<'a, T>,
+    _handoff: Handoff<'a, T>,
+}
+
+impl<T> Deref for PriorityGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for PriorityGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Minimum time between two packets
+    pub short_term_interval: Duration,
+    /// Time it takes to earn back one packet of burst
+    pub long_term_interval: Duration,
+    /// Packets that may be sent at the short-term rate before the long-term one applies
+    pub long_term_burst: u32,
+}
+
+impl From<&AnidbConfig> for RateLimits {
+    fn from(config: &AnidbConfig) -> Self {
+        Self {
+            short_term_interval: Duration::from_secs_f64(config.short_term_interval),
+            long_term_interval: Duration::from_secs_f64(config.long_term_interval),
+            long_term_burst: config.long_term_burst,
+        }
+    }
+}
+
+/// Persisted between runs in the settings table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitState {
+    tokens: f64,
+    updated: DateTime<Utc>,
+    last_request: DateTime<Utc>,
+    backoff_until: Option<DateTime<Utc>>,
+    backoffs: u32,
+}
+
+/// How often the state is saved while nothing out of the ordinary happens
+const PERSIST_INTERVAL: TimeDelta = TimeDelta::seconds(30);
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    state: RateLimitState,
+    persisted: DateTime<Utc>,
+}
+
+impl RateLimiter {
+    pub async fn load(limits: RateLimits) -> Result<Self> {
+        let state = match settings::anidb::rate_limit().await? {
+            Some(state) => state,
+            None => RateLimitState {
+                tokens: limits.long_term_burst as f64,
+                updated: Utc::now(),
+                last_request: DateTime::UNIX_EPOCH,
+                backoff_until: None,
+                backoffs: 0,
+            },
+        };
+
+        Ok(Self { limits, state, persisted: Utc::now() })
+    }
+
+    /// Wait until we're allowed to send a packet, and count it as sent
+    pub async fn wait(&mut self) -> Result<()> {
+        let now = Utc::now();
+
+        if let Some(until) = self.state.backoff_until.filter(|until| *until > now) {
+            log::info!("Backing off from AniDB until {}", until.to_rfc3339());
+
+            if let Ok(delay) = (until - now).to_std() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        let now = Utc::now();
+
+        if let Ok(delay) = (self.next_slot(now) - now).to_std() {
+            tokio::time::sleep(delay).await;
+        }
+
+        let now = Utc::now();
+        self.refill(now);
+        self.state.tokens = (self.state.tokens - 1.).max(0.);
+        self.state.last_request = now;
+
+        if now - self.persisted < PERSIST_INTERVAL {
+            return Ok(());
+        }
+
+        self.persist().await
+    }
+
+    async fn persist(&mut self) -> Result<()> {
+        self.persisted = Utc::now();
+        settings::anidb::set_rate_limit(self.state.clone()).await
+    }
+
+    /// Stop sending anything for a while after AniDB told us to
+    pub async fn back_off(&mut self, code: ResponseCode) -> Result<()> {
+        let base = match code {
+            ResponseCode::Banned => TimeDelta::minutes(30),
+            _ => TimeDelta::minutes(1),
+        };
+
+        let factor = 1 << self.state.backoffs.min(5);
+        let until = Utc::now() + (base * factor).min(TimeDelta::hours(12));
+
+        log::warn!("AniDB responded with {:?}, backing off until {}", code, until);
+
+        self.state.backoff_until = Some(until);
+        self.state.backoffs += 1;
+        self.state.tokens = 0.;
+
+        self.persist().await
+    }
+
+    pub async fn succeeded(&mut self) -> Result<()> {
+        if self.state.backoffs == 0 {
+            return Ok(());
+        }
+
+        self.state.backoffs = 0;
+        self.persist().await
+    }
+
+    /// When the last packet was sent, by this or an earlier run
+    pub fn last_request(&self) -> DateTime<Utc> {
+        self.state.last_request
+    }
+
+    /// How long it would take from `now` to send `packets` more, if nobody else wanted to
+    pub fn estimate(&self, now: DateTime<Utc>, packets: u32) -> Duration {
+        let mut limiter = RateLimiter {
+            limits: self.limits.clone(),
+            state: self.state.clone(),
+            persisted: self.persisted,
+        };
+
+        let mut time = limiter.state.backoff_until.unwrap_or(now).max(now);
+
+        for _ in 0..packets {
+            time = limiter.next_slot(time).max(time);
+            limiter.refill(time);
+            limiter.state.tokens = (limiter.state.tokens - 1.).max(0.);
+            limiter.state.last_request = time;
+        }
+
+        (time - now).to_std().unwrap_or_default()
+    }
+
+    fn next_slot(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let short_term = self.state.last_request + self.limits.short_term_interval;
+
+        let mut limiter = RateLimiter {
+            limits: self.limits.clone(),
+            state: self.state.clone(),
+            persisted: self.persisted,
+        };
+        limiter.refill(now);
+
+        let missing = 1. - limiter.state.tokens;
+        if missing <= 0. {
+            return short_term;
+        }
+
+        let long_term = now + self.limits.long_term_interval.mul_f64(missing);
+        short_term.max(long_term)
+    }
+
+    fn refill(&mut self, now: DateTime<Utc>) {
+        let burst = self.limits.long_term_burst as f64;
+        let interval = self.limits.long_term_interval.as_secs_f64();
+        let elapsed = ((now - self.state.updated).num_milliseconds() as f64 / 1000.).max(0.);
+
+        self.state.tokens = if interval > 0. {
+            (self.state.tokens + elapsed / interval).min(burst)
+        } else {
+            burst
+        };
+
+        self.state.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn priority_order() {
+        let mutex = Arc::new(PriorityMutex::new(vec![]));
+        let guard = mutex.lock(Priority::Normal).await;
+
+        let mut tasks = vec![];
+        for priority in [Priority::Bulk, Priority::Bulk, Priority::Interactive] {
+            let mutex = mutex.clone();
+            tasks.push(tokio::spawn(async move {
+                mutex.lock(priority).await.push(priority);
+            }));
+
+            // make sure they queue up in this order
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        drop(guard);
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let order = mutex.lock(Priority::Normal).await.clone();
+        assert_eq!(order, vec![Priority::Interactive, Priority::Bulk, Priority::Bulk]);
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter() {
+        let mutex = PriorityMutex::new(());
+        let guard = mutex.lock(Priority::Normal).await;
+
+        let mut waiter = Box::pin(mutex.lock(Priority::Interactive));
+        assert!(futures::poll!(&mut waiter).is_pending());
+
+        // hands the lock to the waiter, which goes away before taking it
+        drop(guard);
+        drop(waiter);
+
+        let lock = tokio::time::timeout(Duration::from_secs(1), mutex.lock(Priority::Bulk));
+        assert!(lock.await.is_ok());
+    }
+
+    #[test]
+    fn long_term_limit() {
+        let now = Utc::now();
+        let limiter = RateLimiter {
+            limits: RateLimits {
+                short_term_interval: Duration::from_secs(2),
+                long_term_interval: Duration::from_secs(4),
+                long_term_burst: 10,
+            },
+            state: RateLimitState {
+                tokens: 0.,
+                updated: now,
+                last_request: now,
+                backoff_until: None,
+                backoffs: 0,
+            },
+            persisted: now,
+        };
+
+        assert_eq!(limiter.next_slot(now), now + TimeDelta::seconds(4));
+        assert_eq!(limiter.estimate(now, 3), Duration::from_secs(12));
+
+        let limiter = RateLimiter {
+            state: RateLimitState { tokens: 5., ..limiter.state },
+            ..limiter
+        };
+
+        assert_eq!(limiter.next_slot(now), now + TimeDelta::seconds(2));
+    }
+}
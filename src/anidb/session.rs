@@ -1,35 +1,70 @@
 use std::{str::FromStr, time::Duration};
 
 use anyhow::{bail, Context, Result};
-use tokio::{
-    net::UdpSocket,
-    time::{timeout_at, Instant},
-};
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::{net::UdpSocket, time::timeout};
 
 use super::{
     command_builder::CommandBuilder,
-    records::{Anime, Episode, File, Group},
-    response::{codes::ResponseCode, Response},
+    encryption::Cipher,
+    records::{
+        Anime, Character, Creator, DescriptionPart, Episode, File, Group, Mask, MaskField,
+        MaskedRecord, MylistEntry,
+    },
+    response::{codes::ResponseCode, Response, Truncated, Undecryptable},
+    scheduler::RateLimiter,
+    titles, MylistAdd, MylistUpdate,
 };
-use crate::db::settings;
+use crate::{config::AnidbConfig, db::settings};
+
+/// AniDB's default and maximum MTU
+const MAX_PACKET: usize = 1400;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// AniDB forgets about sessions that have been idle for this long
+const SESSION_TIMEOUT: TimeDelta = TimeDelta::minutes(30);
 
 pub struct Session {
     key: Option<String>,
-    next_call: Instant,
+    key_issued: Option<DateTime<Utc>>,
+    cipher: Option<Cipher>,
+    limiter: RateLimiter,
     socket: UdpSocket,
 }
 
 impl Session {
     pub async fn new() -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:16835")
+        let config = crate::CONFIG.read().await.anidb.clone();
+
+        Self::connect(&config).await
+    }
+
+    pub async fn connect(config: &AnidbConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(&config.bind)
             .await
             .context("Failed to bind UDP socket")?;
 
-        socket.connect("api.anidb.net:9000").await?;
+        socket
+            .connect(&config.server)
+            .await
+            .context("Failed to connect to AniDB")?;
+
+        let key = settings::anidb::session_key().await?;
+
+        // an encrypted session stays encrypted for as long as its key is valid
+        let cipher = match (
+            &key,
+            settings::anidb::api_key().await?,
+            settings::anidb::encryption_salt().await?,
+        ) {
+            (Some(_), Some(api_key), Some(salt)) => Some(Cipher::new(&api_key, &salt)),
+            _ => None,
+        };
 
         Ok(Self {
-            key: settings::anidb::session_key().await?,
-            next_call: Instant::now(),
+            key,
+            key_issued: settings::anidb::session_issued().await?,
+            cipher,
+            limiter: RateLimiter::load(config.into()).await?,
             socket,
         })
     }
@@ -39,6 +74,10 @@ impl Session {
     }
 
     pub async fn session_key(&mut self) -> Result<&str> {
+        if self.key_expired() {
+            self.key = None;
+        }
+
         if let Some(ref key) = self.key {
             return Ok(key);
         }
@@ -46,41 +85,56 @@ impl Session {
         self.login().await
     }
 
+    /// Whether AniDB has most likely dropped the session by now
+    fn key_expired(&self) -> bool {
+        let Some(issued) = self.key_issued else {
+            return true;
+        };
+
+        Utc::now() - issued.max(self.limiter.last_request()) > SESSION_TIMEOUT
+    }
+
     pub async fn request(&mut self, mut cmd: CommandBuilder) -> Result<Response> {
         if self.requires_auth(&cmd) {
             cmd = cmd.arg("s", self.session_key().await?);
         }
 
-        let res = self.request_inner(&cmd.to_string()).await?;
-
-        match res.code {
-            ResponseCode::InvalidSession | ResponseCode::LoginFirst => {
+        match self.request_inner(&cmd.to_string()).await {
+            Ok(res)
+                if !matches!(res.code, ResponseCode::InvalidSession | ResponseCode::LoginFirst) =>
+            {
+                Ok(res)
+            }
+            Err(e) if !e.is::<Undecryptable>() => Err(e),
+            // the session is gone, so log in again rather than carry on without encryption
+            _ => {
                 self.key = None;
+                self.cipher = None;
                 let newkey = self.login().await?;
                 cmd = cmd.arg("s", newkey);
                 self.request_inner(&cmd.to_string()).await
             }
-            _ => Ok(res),
         }
     }
 
     pub async fn request_inner(&mut self, cmd: &str) -> Result<Response> {
-        tokio::time::sleep_until(self.next_call).await;
-
-        let mut buf = [0; 1400]; // 1400 is AniDB's default and maximum MTU
+        let mut buf = [0; MAX_PACKET];
 
         let mut retries = 3;
 
         let bytes = loop {
-            self.next_call = Instant::now() + Duration::from_secs(2);
+            self.limiter.wait().await?;
 
             for line in cmd.to_string().lines() {
                 log::trace!("-> {}", line);
             }
 
-            self.socket.send(cmd.as_bytes()).await?;
+            match self.cipher {
+                Some(ref cipher) => self.socket.send(&cipher.encrypt(cmd.as_bytes())).await?,
+                None => self.socket.send(cmd.as_bytes()).await?,
+            };
 
-            match timeout_at(self.next_call, self.socket.recv(&mut buf)).await {
+            match timeout(RESPONSE_TIMEOUT, self.socket.recv(&mut buf)).await {
                 Ok(Ok(read)) => break buf[..read].to_owned(),
                 Ok(Err(e)) => bail!("Failed to read response: {}", e),
                 Err(_) => {
@@ -93,24 +147,84 @@ impl Session {
             }
         };
 
+        if bytes.len() == MAX_PACKET {
+            log::debug!("Response to {} filled a whole packet", cmd.trim_end());
+            bail!(Truncated);
+        }
+
+        let bytes = match self.cipher {
+            Some(ref cipher) => match cipher.decrypt(&bytes) {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    // AniDB answers in plain text once it has forgotten about the session
+                    log::debug!("Received an unencrypted response, dropping the session");
+                    bail!(Undecryptable);
+                }
+            },
+            None => bytes,
+        };
+
         let s = String::from_utf8(bytes)?;
 
         for line in s.lines() {
             log::trace!("<- {}", line);
         }
 
-        Response::from_str(&s).context(format!("Failed to parse response:\n{s}"))
+        let res = Response::from_str(&s).context(format!("Failed to parse response:\n{s}"))?;
+
+        match res.code {
+            ResponseCode::Banned
+            | ResponseCode::AnidbOutOfService
+            | ResponseCode::ServerBusy
+            | ResponseCode::TimeoutDelayAndResubmit => {
+                self.limiter.back_off(res.code).await?;
+                bail!("AniDB refused the request: {:?} {}", res.code, res.message);
+            }
+            _ => self.limiter.succeeded().await?,
+        }
+
+        Ok(res)
+    }
+
+    /// Ask AniDB to encrypt everything from here on, keyed with the user's API key
+    pub async fn encrypt(&mut self, username: &str, api_key: &str) -> Result<()> {
+        self.cipher = None;
+
+        let cmd = CommandBuilder::new("ENCRYPT")
+            .arg("user", username)
+            .arg("type", 1);
+
+        let res = self.request_inner(&cmd.to_string()).await?;
+
+        match res.code {
+            ResponseCode::EncryptionEnabled => (),
+            ResponseCode::ApiPasswordNotDefined => {
+                bail!("No API key is set in the AniDB profile")
+            }
+            ResponseCode::NoSuchUser => bail!("No such AniDB user: {}", username),
+            _ => bail!("Failed to enable encryption: {:?} {}", res.code, res.message),
+        }
+
+        let salt = res.data().context("Missing salt")?.to_string();
+
+        self.cipher = Some(Cipher::new(api_key, &salt));
+        settings::anidb::set_encryption_salt(salt).await?;
+
+        Ok(())
     }
 
     pub async fn login(&mut self) -> Result<&str> {
+        let username = settings::anidb::username()
+            .await
+            .context("Failed to read username")?
+            .context("Username unset")?;
+
+        if let Some(api_key) = settings::anidb::api_key().await? {
+            self.encrypt(&username, &api_key).await?;
+        }
+
         let cmd = CommandBuilder::new("AUTH")
-            .arg(
-                "user",
-                settings::anidb::username()
-                    .await
-                    .context("Failed to read username")?
-                    .context("Username unset")?,
-            )
+            .arg("user", username)
             .arg(
                 "pass",
                 settings::anidb::password()
@@ -134,16 +248,63 @@ impl Session {
         }
 
         self.key = Some(res.data().unwrap().to_string());
+        self.key_issued = Some(Utc::now());
 
         settings::anidb::set_session_key(self.key.clone().unwrap()).await?;
+        settings::anidb::set_session_issued(Utc::now()).await?;
 
         Ok(self.key.as_ref().unwrap())
     }
 
+    /// End the session, so it doesn't count against the concurrent session limit
+    pub async fn logout(&mut self) -> Result<()> {
+        if let Some(key) = self.key.clone().filter(|_| !self.key_expired()) {
+            let cmd = CommandBuilder::new("LOGOUT").arg("s", key);
+            let res = self.request_inner(&cmd.to_string()).await?;
+
+            match res.code {
+                ResponseCode::LoggedOut | ResponseCode::NotLoggedIn => (),
+                _ => log::warn!("Unexpected response to LOGOUT: {:?} {}", res.code, res.message),
+            }
+        }
+
+        self.key = None;
+        self.key_issued = None;
+        self.cipher = None;
+
+        settings::anidb::clear_session_key().await?;
+        settings::anidb::clear_session_issued().await
+    }
+
+    /// Send a command that takes a mask, leaving out fields until the response fits in a packet
+    async fn request_masked<F: MaskField>(
+        &mut self,
+        cmd: CommandBuilder,
+        name: &str,
+        mask: &Mask<F>,
+    ) -> Result<(Response, Mask<F>)> {
+        let mut mask = mask.clone();
+
+        loop {
+            match self.request(cmd.clone().arg(name, &mask)).await {
+                Err(e) if e.is::<Truncated>() => {
+                    let (smaller, dropped) = mask
+                        .shrink()
+                        .context("Response doesn't fit in a packet even with a minimal mask")?;
+
+                    log::warn!("Response too large, requesting again without {:?}", dropped);
+                    mask = smaller;
+                }
+                res => return Ok((res?, mask)),
+            }
+        }
+    }
+
     async fn file_inner(&mut self, cmd: CommandBuilder) -> Result<Option<File>> {
-        let cmd = cmd.arg("fmask", "71c2fef800").arg("amask", "00000000");
+        let cmd = cmd.arg("amask", "00000000");
 
-        let res = self.request(cmd).await?;
+        let full_mask = File::default_mask();
+        let (res, mask) = self.request_masked(cmd, "fmask", &full_mask).await?;
 
         if res.code == ResponseCode::NoSuchFile {
             return Ok(None);
@@ -153,22 +314,26 @@ impl Session {
             bail!("Unexpected response code: {:?}", res.code);
         }
 
-        let item = res.records_as::<File>().next();
+        let item = res.records.first().map(|record| {
+            File::parse_masked(record, &mask).context(format!("Invalid file: {record}"))
+        });
 
-        match item.transpose().map_err(Into::into) {
+        match item.transpose() {
             Ok(Some(file)) => {
                 let json = serde_json::to_string(&file)?;
+                let partial = mask != full_mask;
 
                 sqlx::query!(
-                    "INSERT INTO files (fid, aid, eid, gid, size, ed2k, json)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                    "INSERT INTO files (fid, aid, eid, gid, size, ed2k, json, partial)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
                     ON CONFLICT (fid) DO UPDATE SET
                         aid = $2,
                         eid = $3,
                         gid = $4,
                         size = $5,
                         ed2k = $6,
-                        json = $7",
+                        json = $7,
+                        partial = $8",
                     file.fid,
                     file.aid,
                     file.eid,
@@ -176,6 +341,7 @@ impl Session {
                     file.size,
                     file.ed2k,
                     json,
+                    partial,
                 )
                 .execute(crate::DB.get().await)
                 .await?;
@@ -201,11 +367,10 @@ impl Session {
     }
 
     pub async fn anime_by_aid(&mut self, aid: u32) -> Result<Option<Anime>> {
-        let cmd = CommandBuilder::new("ANIME")
-            .arg("aid", aid)
-            .arg("amask", "fce8ba014080f8");
+        let cmd = CommandBuilder::new("ANIME").arg("aid", aid);
 
-        let res = self.request(cmd).await?;
+        let full_mask = Anime::default_mask();
+        let (res, mask) = self.request_masked(cmd, "amask", &full_mask).await?;
 
         if res.code == ResponseCode::NoSuchAnime {
             return Ok(None);
@@ -215,23 +380,30 @@ impl Session {
             bail!("Unexpected response code: {:?}", res.code);
         }
 
-        let item = res.records_as::<Anime>().next();
+        let item = res.records.first().map(|record| {
+            Anime::parse_masked(record, &mask).context(format!("Invalid anime: {record}"))
+        });
 
-        match item.transpose().map_err(Into::into) {
+        match item.transpose() {
             Ok(Some(anime)) => {
                 let json = serde_json::to_string(&anime)?;
+                let partial = mask != full_mask;
 
                 sqlx::query!(
-                    "INSERT INTO anime (aid, json)
-                    VALUES ($1, $2)
+                    "INSERT INTO anime (aid, json, partial)
+                    VALUES ($1, $2, $3)
                     ON CONFLICT (aid) DO UPDATE SET
-                        json = $2",
+                        json = $2,
+                        partial = $3",
                     anime.aid,
                     json,
+                    partial,
                 )
                 .execute(crate::DB.get().await)
                 .await?;
 
+                titles::add_cached(&anime).await?;
+
                 let link = sqlx::query!(
                     "SELECT id FROM platform_links
                     WHERE anidb_id = $1 OR ann_id = $2",
@@ -269,9 +441,132 @@ impl Session {
         }
     }
 
+    /// Fetch every part of the description and put them together
+    pub async fn anime_description(&mut self, aid: u32) -> Result<Option<String>> {
+        let mut description = String::new();
+        let mut part = 0;
+
+        loop {
+            let cmd = CommandBuilder::new("ANIMEDESC")
+                .arg("aid", aid)
+                .arg("part", part);
+
+            let res = self.request(cmd).await?;
+
+            match res.code {
+                ResponseCode::AnimeDescription => (),
+                ResponseCode::NoSuchAnime | ResponseCode::NoSuchDescription => {
+                    cache_description(aid, None).await?;
+                    return Ok(None);
+                }
+                _ => bail!("Unexpected response code: {:?}", res.code),
+            }
+
+            let desc = res
+                .records_as::<DescriptionPart>()
+                .next()
+                .context("Empty description")??;
+
+            description += &desc.text;
+            part += 1;
+
+            if part >= desc.max_parts {
+                break;
+            }
+        }
+
+        cache_description(aid, Some(&description)).await?;
+
+        Ok(Some(description))
+    }
+
+    pub async fn character_by_id(&mut self, charid: u32) -> Result<Option<Character>> {
+        let cmd = CommandBuilder::new("CHARACTER").arg("charid", charid);
+
+        let res = self.request(cmd).await?;
+
+        if res.code == ResponseCode::NoSuchCharacter {
+            return Ok(None);
+        }
+
+        if res.code != ResponseCode::Character {
+            bail!("Unexpected response code: {:?}", res.code);
+        }
+
+        let item = res.records_as::<Character>().next();
+
+        match item.transpose() {
+            Ok(Some(character)) => {
+                let json = serde_json::to_string(&character)?;
+
+                sqlx::query!(
+                    "INSERT INTO characters (charid, json)
+                    VALUES ($1, $2)
+                    ON CONFLICT (charid) DO UPDATE SET
+                        json = $2",
+                    character.charid,
+                    json,
+                )
+                .execute(crate::DB.get().await)
+                .await?;
+
+                Ok(Some(character))
+            }
+            v => v,
+        }
+    }
+
+    pub async fn creator_by_id(&mut self, creatorid: u32) -> Result<Option<Creator>> {
+        let cmd = CommandBuilder::new("CREATOR").arg("creatorid", creatorid);
+
+        let res = self.request(cmd).await?;
+
+        if res.code == ResponseCode::NoSuchCreator {
+            return Ok(None);
+        }
+
+        if res.code != ResponseCode::Creator {
+            bail!("Unexpected response code: {:?}", res.code);
+        }
+
+        let item = res.records_as::<Creator>().next();
+
+        match item.transpose() {
+            Ok(Some(creator)) => {
+                let json = serde_json::to_string(&creator)?;
+
+                sqlx::query!(
+                    "INSERT INTO creators (creatorid, json)
+                    VALUES ($1, $2)
+                    ON CONFLICT (creatorid) DO UPDATE SET
+                        json = $2",
+                    creator.creatorid,
+                    json,
+                )
+                .execute(crate::DB.get().await)
+                .await?;
+
+                Ok(Some(creator))
+            }
+            v => v,
+        }
+    }
+
     pub async fn episode_by_eid(&mut self, eid: u32) -> Result<Option<Episode>> {
-        let cmd = CommandBuilder::new("EPISODE").arg("eid", eid);
+        self.episode(CommandBuilder::new("EPISODE").arg("eid", eid))
+            .await
+    }
+
+    pub async fn episode_by_epno(&mut self, aid: u32, epno: &str) -> Result<Option<Episode>> {
+        self.episode(
+            CommandBuilder::new("EPISODE")
+                .arg("aid", aid)
+                .arg("epno", epno),
+        )
+        .await
+    }
 
+    async fn episode(&mut self, cmd: CommandBuilder) -> Result<Option<Episode>> {
         let res = self.request(cmd).await?;
 
         if res.code == ResponseCode::NoSuchEpisode {
@@ -284,7 +579,7 @@ impl Session {
 
         let item = res.records_as::<Episode>().next();
 
-        match item.transpose().map_err(Into::into) {
+        match item.transpose() {
             Ok(Some(episode)) => {
                 let json = serde_json::to_string(&episode)?;
 
@@ -322,17 +617,21 @@ impl Session {
 
         let item = res.records_as::<Group>().next();
 
-        match item.transpose().map_err(Into::into) {
+        match item.transpose() {
             Ok(Some(group)) => {
                 let json = serde_json::to_string(&group)?;
 
                 sqlx::query!(
-                    "INSERT INTO groups (gid, json)
-                    VALUES ($1, $2)
+                    "INSERT INTO groups (gid, json, name, short)
+                    VALUES ($1, $2, $3, $4)
                     ON CONFLICT (gid) DO UPDATE SET
-                        json = $2",
+                        json = $2,
+                        name = $3,
+                        short = $4",
                     group.gid,
                     json,
+                    group.name,
+                    group.short,
                 )
                 .execute(crate::DB.get().await)
                 .await?;
@@ -342,4 +641,283 @@ impl Session {
             v => v,
         }
     }
+
+    async fn mylist_inner(&mut self, cmd: CommandBuilder) -> Result<Option<MylistEntry>> {
+        let res = self.request(cmd).await?;
+
+        if res.code == ResponseCode::NoSuchEntry {
+            return Ok(None);
+        }
+
+        if res.code != ResponseCode::Mylist {
+            bail!("Unexpected response code: {:?}", res.code);
+        }
+
+        let item = res.records_as::<MylistEntry>().next();
+
+        match item.transpose() {
+            Ok(Some(entry)) => {
+                cache_mylist_entry(&entry).await?;
+                Ok(Some(entry))
+            }
+            v => v,
+        }
+    }
+
+    pub async fn mylist_by_lid(&mut self, lid: u32) -> Result<Option<MylistEntry>> {
+        let cmd = CommandBuilder::new("MYLIST").arg("lid", lid);
+
+        self.mylist_inner(cmd).await
+    }
+
+    /// Also forgets the cached entry if the file isn't in the mylist anymore
+    pub async fn mylist_by_fid(&mut self, fid: u32) -> Result<Option<MylistEntry>> {
+        let cmd = CommandBuilder::new("MYLIST").arg("fid", fid);
+
+        let entry = self.mylist_inner(cmd).await?;
+
+        if entry.is_none() {
+            sqlx::query!("DELETE FROM mylist WHERE fid = $1", fid)
+                .execute(crate::DB.get().await)
+                .await?;
+        }
+
+        Ok(entry)
+    }
+
+    pub async fn mylist_add(&mut self, fid: u32, update: &MylistUpdate) -> Result<MylistAdd> {
+        let cmd = mylist_update_args(CommandBuilder::new("MYLISTADD").arg("fid", fid), update);
+
+        let res = self.request(cmd).await?;
+
+        match res.code {
+            ResponseCode::MylistEntryAdded => {
+                let lid = res
+                    .records
+                    .first()
+                    .context("Missing mylist id")?
+                    .parse()
+                    .context("Invalid mylist id")?;
+
+                let entry = self
+                    .mylist_by_lid(lid)
+                    .await?
+                    .context("Added mylist entry has disappeared")?;
+
+                Ok(MylistAdd::Added(entry))
+            }
+            ResponseCode::FileAlreadyInMylist => {
+                let entry = res
+                    .records_as::<MylistEntry>()
+                    .next()
+                    .context("Missing mylist entry")??;
+
+                cache_mylist_entry(&entry).await?;
+
+                Ok(MylistAdd::AlreadyInMylist(entry))
+            }
+            ResponseCode::NoSuchFile => Ok(MylistAdd::NoSuchFile),
+            code => bail!("Unexpected response code: {:?}", code),
+        }
+    }
+
+    pub async fn mylist_edit(&mut self, lid: u32, update: &MylistUpdate) -> Result<bool> {
+        let cmd = CommandBuilder::new("MYLISTADD").arg("lid", lid);
+
+        self.mylist_edit_inner(cmd, Some(lid), None, update).await
+    }
+
+    pub async fn mylist_edit_by_fid(&mut self, fid: u32, update: &MylistUpdate) -> Result<bool> {
+        let cmd = CommandBuilder::new("MYLISTADD").arg("fid", fid);
+
+        self.mylist_edit_inner(cmd, None, Some(fid), update).await
+    }
+
+    async fn mylist_edit_inner(
+        &mut self,
+        cmd: CommandBuilder,
+        lid: Option<u32>,
+        fid: Option<u32>,
+        update: &MylistUpdate,
+    ) -> Result<bool> {
+        let res = self
+            .request(mylist_update_args(cmd.arg("edit", true), update))
+            .await?;
+
+        match res.code {
+            ResponseCode::MylistEntryEdited => (),
+            ResponseCode::NoSuchMylistEntry => {
+                // removed on AniDB's side, so stop tracking it
+                sqlx::query!("DELETE FROM mylist WHERE lid = $1 OR fid = $2", lid, fid)
+                    .execute(crate::DB.get().await)
+                    .await?;
+
+                return Ok(false);
+            }
+            code => bail!("Unexpected response code: {:?}", code),
+        }
+
+        let cache = sqlx::query!("SELECT json FROM mylist WHERE lid = $1 OR fid = $2", lid, fid)
+            .fetch_optional(crate::DB.get().await)
+            .await?;
+
+        if let Some(cache) = cache {
+            let mut entry: MylistEntry =
+                serde_json::from_str(&cache.json).context("Invalid record in database")?;
+
+            update.apply(&mut entry);
+            cache_mylist_entry(&entry).await?;
+        }
+
+        Ok(true)
+    }
+
+    pub async fn mylist_del(&mut self, lid: u32) -> Result<bool> {
+        let cmd = CommandBuilder::new("MYLISTDEL").arg("lid", lid);
+
+        let res = self.request(cmd).await?;
+
+        match res.code {
+            ResponseCode::MylistEntryDeleted => (),
+            ResponseCode::NoSuchMylistEntry => return Ok(false),
+            code => bail!("Unexpected response code: {:?}", code),
+        }
+
+        sqlx::query!("DELETE FROM mylist WHERE lid = $1", lid)
+            .execute(crate::DB.get().await)
+            .await?;
+
+        Ok(true)
+    }
+}
+
+fn mylist_update_args(mut cmd: CommandBuilder, update: &MylistUpdate) -> CommandBuilder {
+    if let Some(state) = update.state {
+        cmd = cmd.arg("state", state as u8);
+    }
+
+    if let Some(viewed) = update.viewed {
+        cmd = cmd.arg("viewed", viewed);
+    }
+
+    if let Some(viewdate) = update.viewdate {
+        cmd = cmd.arg("viewdate", viewdate.timestamp());
+    }
+
+    cmd
+}
+
+/// `None` when AniDB has no description for the anime
+async fn cache_description(aid: u32, description: Option<&str>) -> Result<()> {
+    let now = Utc::now().timestamp();
+
+    sqlx::query!(
+        "INSERT INTO anime_descriptions (aid, description, fetched)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (aid) DO UPDATE SET
+            description = $2,
+            fetched = $3",
+        aid,
+        description,
+        now,
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(())
+}
+
+async fn cache_mylist_entry(entry: &MylistEntry) -> Result<()> {
+    let json = serde_json::to_string(entry)?;
+    let now = Utc::now().timestamp();
+
+    sqlx::query!(
+        "INSERT OR REPLACE INTO mylist (lid, fid, json, fetched)
+        VALUES ($1, $2, $3, $4)",
+        entry.lid,
+        entry.fid,
+        json,
+        now,
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::mock::{fixtures, MockServer};
+
+    #[tokio::test]
+    async fn encrypted_session() {
+        let server = MockServer::logged_in().await;
+        server.set_api_key("secret");
+        server.add_anime(fixtures::ANIME).unwrap();
+
+        let mut session = Session::connect(&server.config()).await.unwrap();
+        session.key = None;
+        session.encrypt("tetsu", "secret").await.unwrap();
+
+        let anime = session.anime_by_aid(1).await.unwrap().unwrap();
+        assert_eq!(anime.aid, 1);
+
+        // the password and session key never went out in plain text
+        assert_eq!(server.unencrypted_requests(), vec!["ENCRYPT user=tetsu&type=1"]);
+
+        // a server that forgot the session answers in plain text, which isn't trusted
+        server.forget_sessions();
+        let res = session.request_inner("PING").await;
+        assert!(res.is_err_and(|e| e.is::<Undecryptable>()));
+
+        // and means logging in again
+        let anime = session.anime_by_aid(1).await.unwrap().unwrap();
+        assert_eq!(anime.aid, 1);
+        let logins = server
+            .requests()
+            .iter()
+            .filter(|req| req.starts_with("AUTH"))
+            .count();
+        assert_eq!(logins, 2);
+    }
+
+    #[tokio::test]
+    async fn truncated_response() {
+        let server = MockServer::logged_in().await;
+        let description = "x".repeat(1500);
+        let record = format!("210|1|1|1|1|4096|{}||||8|high|DVD|AAC|128|H264/AVC|1500|1280x720|japanese|english|1500|{description}|946684800", "f".repeat(32));
+        server.add_file(&record).unwrap();
+
+        let mut session = Session::connect(&server.config()).await.unwrap();
+
+        let file = session.file_by_fid(210).await.unwrap().unwrap();
+        assert_eq!(file.size, 4096);
+        assert_eq!(file.description, "");
+
+        let last = server.requests().pop().unwrap();
+        assert!(last.contains("fmask=71fafee800"), "{last}");
+
+        // missing the description, so it's not served from the cache
+        let partial = sqlx::query_scalar!("SELECT partial FROM files WHERE fid = 210")
+            .fetch_one(crate::DB.get().await)
+            .await
+            .unwrap();
+        assert_eq!(partial, 1);
+    }
+
+    #[tokio::test]
+    async fn logout() {
+        let server = MockServer::logged_in().await;
+
+        let mut session = Session::connect(&server.config()).await.unwrap();
+        session.key = None;
+
+        let key = session.session_key().await.unwrap().to_string();
+        assert!(!session.key_expired());
+
+        session.logout().await.unwrap();
+        assert!(session.key.is_none());
+        assert_eq!(server.requests().last().unwrap(), &format!("LOGOUT s={key}"));
+    }
 }
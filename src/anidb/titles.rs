@@ -0,0 +1,361 @@
+//! Offline title search, backed by AniDB's daily title dump. The dump can be downloaded
+//! from <https://anidb.net/api/anime-titles.dat.gz> (or `.xml.gz`) at most once a day, and
+//! is imported as a whole with [`import`]. Titles of anime we've cached are added as
+//! they come in with [`add_cached`].
+
+use std::{fs, io::Read, path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use serde::Serialize;
+
+use super::records::Anime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleType {
+    Main,
+    Synonym,
+    Short,
+    Official,
+}
+
+impl TitleType {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Main => "main",
+            Self::Synonym => "syn",
+            Self::Short => "short",
+            Self::Official => "official",
+        }
+    }
+}
+
+impl FromStr for TitleType {
+    type Err = anyhow::Error;
+
+    // the .dat dump uses numbers, the XML one names
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "1" | "main" => Ok(Self::Main),
+            "2" | "syn" => Ok(Self::Synonym),
+            "3" | "short" => Ok(Self::Short),
+            "4" | "official" => Ok(Self::Official),
+            _ => bail!("Unknown title type: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Title {
+    pub aid: u32,
+    pub title_type: TitleType,
+    pub language: String,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TitleMatch {
+    pub aid: u32,
+    pub main_title: String,
+    /// The title that matched the query, which may be a synonym in another language
+    pub title: String,
+    pub language: String,
+}
+
+/// Replace the titles table with the contents of a dump, gzipped or not, in either format.
+/// Titles of cached anime are added back, in case the dump doesn't know them.
+pub async fn import(path: &Path) -> Result<usize> {
+    let titles = parse(&read_dump(path)?)?;
+
+    let mut tx = crate::DB.get().await.begin().await?;
+
+    sqlx::query!("DELETE FROM anime_titles")
+        .execute(&mut *tx)
+        .await?;
+
+    for title in titles.iter() {
+        let title_type = title.title_type.as_str();
+
+        sqlx::query!(
+            "INSERT INTO anime_titles (aid, type, language, title)
+            VALUES ($1, $2, $3, $4)",
+            title.aid,
+            title_type,
+            title.language,
+            title.title,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    sqlx::query!(
+        "INSERT INTO anime_titles (aid, type, language, title)
+        SELECT aid, type, language, title FROM (
+            SELECT aid, 'main' AS type, 'x-jat' AS language,
+                json_extract(json, '$.romaji_name') AS title
+            FROM anime
+            UNION
+            SELECT aid, 'official', 'en', json_extract(json, '$.english_name') FROM anime
+            UNION
+            SELECT aid, 'official', 'ja', json_extract(json, '$.kanji_name') FROM anime
+            UNION
+            SELECT anime.aid, 'short', '', short.value
+            FROM anime, json_each(anime.json, '$.short_name_list') short
+        ) cached
+        WHERE coalesce(title, '') != ''
+            AND NOT EXISTS (
+                SELECT 1 FROM anime_titles t WHERE t.aid = cached.aid AND t.title = cached.title
+            )"
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("INSERT INTO anime_titles_fts (anime_titles_fts) VALUES ('rebuild')")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(titles.len())
+}
+
+/// Make a cached anime findable by its names, which the dump might not have yet
+pub async fn add_cached(anime: &Anime) -> Result<()> {
+    let db = crate::DB.get().await;
+
+    let names = [
+        (TitleType::Main, "x-jat", &anime.romaji_name),
+        (TitleType::Official, "en", &anime.english_name),
+        (TitleType::Official, "ja", &anime.kanji_name),
+    ]
+    .into_iter()
+    .chain(
+        anime
+            .short_name_list
+            .iter()
+            .map(|name| (TitleType::Short, "", name)),
+    );
+
+    for (title_type, language, title) in names.filter(|(_, _, title)| !title.is_empty()) {
+        let title_type = title_type.as_str();
+
+        let inserted = sqlx::query!(
+            "INSERT INTO anime_titles (aid, type, language, title)
+            SELECT $1, $2, $3, $4
+            WHERE NOT EXISTS (SELECT 1 FROM anime_titles WHERE aid = $1 AND title = $4)",
+            anime.aid,
+            title_type,
+            language,
+            title,
+        )
+        .execute(db)
+        .await?;
+
+        if inserted.rows_affected() > 0 {
+            let rowid = inserted.last_insert_rowid();
+
+            sqlx::query!(
+                "INSERT INTO anime_titles_fts (rowid, title) VALUES ($1, $2)",
+                rowid,
+                title,
+            )
+            .execute(db)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Best matches first, one per anime
+pub async fn search(query: &str, limit: u32) -> Result<Vec<TitleMatch>> {
+    let query = fts_query(query);
+
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let rows = sqlx::query!(
+        r#"SELECT
+            t.aid AS "aid!: u32",
+            t.title AS "title!",
+            t.language AS "language!",
+            min(anime_titles_fts.rank) AS "rank: f64",
+            (SELECT m.title FROM anime_titles m WHERE m.aid = t.aid AND m.type = 'main')
+                AS "main_title?: String"
+        FROM anime_titles_fts
+        INNER JOIN anime_titles t ON t.rowid = anime_titles_fts.rowid
+        WHERE anime_titles_fts MATCH $1
+        GROUP BY t.aid
+        ORDER BY min(anime_titles_fts.rank)
+        LIMIT $2"#,
+        query,
+        limit,
+    )
+    .fetch_all(crate::DB.get().await)
+    .await
+    .context("Title search failed")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| TitleMatch {
+            aid: row.aid,
+            main_title: row.main_title.unwrap_or_else(|| row.title.clone()),
+            title: row.title,
+            language: row.language,
+        })
+        .collect())
+}
+
+/// Every word as a quoted prefix, so user input can't inject FTS syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn read_dump(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).context("Failed to read title dump")?;
+
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut s = String::new();
+        GzDecoder::new(&bytes[..])
+            .read_to_string(&mut s)
+            .context("Failed to decompress title dump")?;
+        Ok(s)
+    } else {
+        String::from_utf8(bytes).context("Title dump isn't valid UTF-8")
+    }
+}
+
+fn parse(dump: &str) -> Result<Vec<Title>> {
+    if dump.trim_start().starts_with('<') {
+        parse_xml(dump)
+    } else {
+        parse_dat(dump)
+    }
+}
+
+// aid|type|language|title, with # comments
+fn parse_dat(dump: &str) -> Result<Vec<Title>> {
+    dump.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.splitn(4, '|');
+            let mut next = || parts.next().context(format!("Invalid title line: {line}"));
+
+            Ok(Title {
+                aid: next()?.parse()?,
+                title_type: next()?.parse()?,
+                language: next()?.to_string(),
+                title: next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+// <animetitles><anime aid="1"><title xml:lang="x-jat" type="main">...</title></anime></animetitles>
+fn parse_xml(dump: &str) -> Result<Vec<Title>> {
+    let doc = roxmltree::Document::parse(dump).context("Invalid title dump")?;
+    let mut titles = vec![];
+
+    for anime in doc
+        .root_element()
+        .children()
+        .filter(|n| n.has_tag_name("anime"))
+    {
+        let aid = anime
+            .attribute("aid")
+            .context("Anime without aid")?
+            .parse()?;
+
+        for title in anime.children().filter(|n| n.has_tag_name("title")) {
+            titles.push(Title {
+                aid,
+                title_type: title
+                    .attribute("type")
+                    .context("Title without type")?
+                    .parse()?,
+                language: title
+                    .attribute((roxmltree::NS_XML_URI, "lang"))
+                    .unwrap_or_default()
+                    .to_string(),
+                title: title.text().unwrap_or_default().to_string(),
+            });
+        }
+    }
+
+    Ok(titles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAT: &str = "# created: Sat Oct 17 2026
+# <aid>|<type>|<language>|<title>
+1|1|x-jat|Seikai no Monshou
+1|4|en|Crest of the Stars
+1|3|en|CotS
+2|1|x-jat|Seikai no Senki
+2|4|en|Banner of the Stars
+";
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<animetitles>
+  <anime aid="1">
+    <title xml:lang="x-jat" type="main">Seikai no Monshou</title>
+    <title xml:lang="en" type="official">Crest of the Stars</title>
+  </anime>
+</animetitles>"#;
+
+    #[test]
+    fn formats_agree() {
+        let dat = parse(DAT).unwrap();
+        let xml = parse(XML).unwrap();
+
+        assert_eq!(dat.len(), 5);
+        assert_eq!(xml, dat[..2]);
+    }
+
+    #[tokio::test]
+    async fn import_and_search() {
+        let path = std::env::temp_dir().join(format!("tetsu-titles-{}.dat", std::process::id()));
+        fs::write(&path, DAT).unwrap();
+
+        let anime = Anime {
+            aid: 700,
+            romaji_name: "Mouretsu Pirates".to_string(),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&anime).unwrap();
+        sqlx::query!("INSERT INTO anime (aid, json) VALUES (700, ?)", json)
+            .execute(crate::DB.get().await)
+            .await
+            .unwrap();
+        add_cached(&anime).await.unwrap();
+
+        assert_eq!(import(&path).await.unwrap(), 5);
+        fs::remove_file(&path).unwrap();
+
+        // other tests cache anime too, so only the ones from the dump are looked for
+        let matches = search("star", 10).await.unwrap();
+        assert!([1, 2]
+            .iter()
+            .all(|aid| matches.iter().any(|m| m.aid == *aid)));
+
+        let matches = search("crest", 10).await.unwrap();
+        let crest = matches.iter().find(|m| m.aid == 1).unwrap();
+        assert_eq!(crest.main_title, "Seikai no Monshou");
+        assert_eq!(crest.title, "Crest of the Stars");
+
+        // cached anime the dump doesn't have are still found
+        let matches = search("mouretsu", 10).await.unwrap();
+        assert_eq!(matches[0].aid, 700);
+
+        assert!(search("\"", 10).await.unwrap().is_empty());
+    }
+}
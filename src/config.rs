@@ -2,12 +2,128 @@ use std::{env, fs, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
+use crate::indexer::{ed2k::Hasher, organize::Mode};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub db_path: PathBuf,
+    #[serde(default)]
+    pub anidb: AnidbConfig,
+    #[serde(default)]
+    pub index: IndexConfig,
+    #[serde(default)]
+    pub organize: OrganizeConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnidbConfig {
+    /// Address of the AniDB UDP API
+    pub server: String,
+    /// Local address to bind the UDP socket to
+    pub bind: String,
+    /// Mark an episode as watched on AniDB once this much of it has been played, in percent.
+    /// Anything above 100 disables this.
+    pub watched_percent: f32,
+    /// Minimum number of seconds between two requests
+    pub short_term_interval: f64,
+    /// Seconds between requests once the burst allowance is used up
+    pub long_term_interval: f64,
+    /// Number of requests that can be sent at the short-term rate
+    pub long_term_burst: u32,
+    /// Where AniDB pictures are looked for, named by their `picname`.
+    /// `$XDG_CACHE_HOME/tetsu/images` if not set
+    pub image_cache: Option<PathBuf>,
+}
+
+impl Default for AnidbConfig {
+    fn default() -> Self {
+        Self {
+            server: "api.anidb.net:9000".to_string(),
+            bind: "0.0.0.0:16835".to_string(),
+            watched_percent: 85.,
+            short_term_interval: 2.,
+            long_term_interval: 4.,
+            long_term_burst: 60,
+            image_cache: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexConfig {
+    /// Directories to keep indexed while running in server mode
+    pub watch: Vec<PathBuf>,
+    /// Add files found by the watcher to the user's AniDB mylist
+    pub add_to_mylist: bool,
+    /// Number of files hashed at the same time
+    pub hash_jobs: usize,
+    /// Number of files read at the same time from any one device
+    pub io_per_device: usize,
+    /// `mmap`, or `read` for network mounts where mapping files is slow
+    pub hasher: Hasher,
+    /// Also compute CRC32, MD5 and SHA1 while hashing, for `tetsu verify`
+    pub extra_hashes: bool,
+    /// Only index files matching one of these globs, if there are any
+    pub include: Vec<String>,
+    /// Never index files matching these globs, or anything in matching directories
+    pub exclude: Vec<String>,
+    /// Only index files with these extensions, empty to allow any
+    pub extensions: Vec<String>,
+    /// Skip files and directories starting with a dot
+    pub skip_hidden: bool,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            watch: vec![],
+            add_to_mylist: false,
+            hash_jobs: 4,
+            io_per_device: 1,
+            hasher: Hasher::Mmap,
+            extra_hashes: false,
+            include: vec![],
+            exclude: vec!["*.part".to_string(), "*.!qB".to_string()],
+            extensions: [
+                "mkv", "mp4", "avi", "ogm", "m4v", "webm", "wmv", "mov", "flv", "rm", "rmvb",
+                "mpg", "mpeg", "ts", "m2ts",
+            ]
+            .map(String::from)
+            .to_vec(),
+            skip_hidden: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrganizeConfig {
+    /// Where `tetsu organize` puts files, if not given on the command line
+    pub root: Option<PathBuf>,
+    /// Path of a file below the root. Placeholders like `{anime.romaji_name}` take fields
+    /// from the file's anime, episode, group or file record, `|` separates fallbacks for
+    /// empty fields and `:2` zero-pads numbers.
+    pub template: String,
+    /// `move`, `hardlink` or `symlink`
+    pub mode: Mode,
+}
+
+impl Default for OrganizeConfig {
+    fn default() -> Self {
+        Self {
+            root: None,
+            template:
+                "{anime.romaji_name}/{episode.epno:2} - {episode.romaji} [{group.short}].{ext}"
+                    .to_string(),
+            mode: Mode::Move,
+        }
+    }
 }
 
 impl Config {
+    #[cfg(not(test))]
     pub fn read() -> Self {
         let config_path = PathBuf::from(
             env::var("HOME").expect("$HOME is not set") + "/.config/tetsu/config.toml",
@@ -23,4 +139,18 @@ impl Config {
 
         toml::from_str(&config).unwrap()
     }
+
+    /// Tests get a fresh database per run and never touch the user's config
+    #[cfg(test)]
+    pub fn read() -> Self {
+        let db_path = env::temp_dir().join(format!("tetsu-test-{}.db", std::process::id()));
+        let _ = fs::remove_file(&db_path);
+
+        Self {
+            db_path,
+            anidb: AnidbConfig::default(),
+            index: IndexConfig::default(),
+            organize: OrganizeConfig::default(),
+        }
+    }
 }
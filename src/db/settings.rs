@@ -28,6 +28,17 @@ macro_rules! setting {
                     .map(|_| ())
                     .map_err(|e| e.into())
             }
+
+            pub async fn [< clear_ $name >]() -> anyhow::Result<()> {
+                sqlx::query!(
+                    "DELETE FROM settings WHERE key = $1",
+                    concat!(stringify!($prefix), "_", stringify!($name)),
+                )
+                    .execute(crate::DB.get().await)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.into())
+            }
         }
     };
 }
@@ -36,6 +47,10 @@ pub mod anidb {
     setting!(anidb username(String));
     setting!(anidb password(String));
     setting!(anidb session_key(String));
+    setting!(anidb session_issued(chrono::DateTime<chrono::Utc>));
+    setting!(anidb api_key(String));
+    setting!(anidb encryption_salt(String));
+    setting!(anidb rate_limit(crate::anidb::RateLimitState));
 }
 
 pub mod animebytes {
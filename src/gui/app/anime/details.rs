@@ -3,12 +3,32 @@ use egui::{Context, Ui};
 use futures::{StreamExt as _, TryStreamExt as _};
 
 use crate::{
-    anidb::records::{Anime, Episode, File, Group},
+    anidb::{
+        records::{Anime, Episode, File, Group},
+        Anidb, Priority,
+    },
     gui::app::{
         autofocus::AutofocusExt as _, future_state::FutureState, page::{Page, PageAction}
     },
+    indexer::link,
 };
 
+#[derive(Clone, Hash)]
+struct Description {
+    aid: u32,
+}
+
+impl FutureState for Description {
+    type State = Option<String>;
+
+    async fn load(self, _ctx: Context) -> Result<Self::State> {
+        Anidb::new()
+            .with_priority(Priority::Interactive)
+            .anime_description(self.aid)
+            .await
+    }
+}
+
 #[derive(Clone, Hash)]
 struct Episodes {
     aid: u32,
@@ -51,6 +71,7 @@ impl FutureState for Episodes {
             (alpha.to_string(), num)
         });
 
+        let linked = link::linked_files(self.aid).await?;
         let mut listings = vec![];
 
         for episode in episodes {
@@ -69,19 +90,32 @@ impl FutureState for Episodes {
                 let group: Group =
                     serde_json::from_str(&row.gjson).context("Invalid record in database")?;
 
-                let paths_on_disk =
-                    sqlx::query_scalar!("SELECT path FROM indexed_files WHERE fid = ?", file.fid)
-                        .fetch_all(db)
-                        .await?;
+                let paths_on_disk = sqlx::query_scalar!(
+                    "SELECT path FROM indexed_files WHERE fid = ? AND missing_since IS NULL",
+                    file.fid
+                )
+                .fetch_all(db)
+                .await?;
 
                 Result::<_, anyhow::Error>::Ok(FileListing { file, group, paths_on_disk })
             });
 
-            let files = tokio_stream::iter(queries)
+            let mut files: Vec<FileListing> = tokio_stream::iter(queries)
                 .buffer_unordered(10)
-                .try_collect::<Vec<FileListing>>()
+                .try_collect()
                 .await?;
 
+            files.extend(
+                linked
+                    .iter()
+                    .filter(|linked| linked.file.eid == episode.eid)
+                    .map(|linked| FileListing {
+                        file: linked.file.clone(),
+                        group: linked.group.clone(),
+                        paths_on_disk: vec![linked.path.clone()],
+                    }),
+            );
+
             if !files.is_empty() {
                 listings.push(EpisodeListing { episode, files });
             }
@@ -101,6 +135,12 @@ impl Page for AnimeDetails {
             action = Some(PageAction::Pop);
         }
 
+        ui.add(Description { aid: self.0.aid }.ready_ui(|ui, state| {
+            if let Some(description) = state {
+                ui.label(description.as_str());
+            }
+        }));
+
         ui.add(Episodes { aid: self.0.aid }.ready_ui(|ui, state| {
             for item in state {
                 if ui.button(&item.episode.romaji).autofocus(ui.ctx()).clicked() {
@@ -7,7 +7,7 @@ use axum::{
 };
 use tokio::{net::TcpListener, sync::RwLock};
 
-use crate::anidb::Anidb;
+use crate::anidb::{outbox, Anidb, Priority};
 
 mod error;
 mod routes;
@@ -15,13 +15,18 @@ mod routes;
 type Result<T> = std::result::Result<T, error::AppError>;
 
 pub async fn run() -> anyhow::Result<()> {
-    let anidb = Arc::new(RwLock::new(Anidb::new()));
+    let anidb = Arc::new(RwLock::new(Anidb::new().with_priority(Priority::Interactive)));
+
+    tokio::spawn(outbox::run(Arc::new(RwLock::new(Anidb::new()))));
 
     let app = Router::new()
         .route("/anime", get(routes::all_anime))
         .route("/anime/:aid", get(routes::anime))
+        .route("/anime/:aid/characters", get(routes::anime_characters))
         .route("/anime/:aid/episodes", get(routes::anime_episodes))
         .route("/anime/:aid/files", get(routes::anime_files))
+        .route("/anime/:aid/related", get(routes::anime_related))
+        .route("/files/link", post(routes::link_file))
         .route("/mpv", get(routes::mpv::mpv_upgrade))
         .route("/report-progress", post(routes::report_progress))
         .route("/settings", get(routes::settings::get))
@@ -30,6 +35,7 @@ pub async fn run() -> anyhow::Result<()> {
         .route("/animebytes/groups/:id", get(routes::proxy::animebytes::group))
         .route("/animebytes/torrents/:id", get(routes::proxy::animebytes::torrent))
         .route("/platform_links", get(routes::platform_links::get))
+        .route("/titles/search", get(routes::search_titles))
         .with_state(anidb);
 
     let listener = TcpListener::bind("127.0.0.1:5352").await?;
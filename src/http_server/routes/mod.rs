@@ -1,8 +1,12 @@
-use std::{num::NonZeroU64, sync::Arc};
+use std::{
+    collections::BTreeSet,
+    num::NonZeroU64,
+    sync::{Arc, Mutex as StdMutex},
+};
 
 use anyhow::Context;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     Json,
 };
 use chrono::{DateTime, Utc};
@@ -11,9 +15,15 @@ use tokio::sync::RwLock;
 
 use self::platform_links::PlatformLinks;
 use super::Result;
-use crate::anidb::{
-    records::{Anime, Episode, File},
-    Anidb,
+use crate::{
+    anidb::{
+        outbox,
+        records::{Anime, Character, Creator, Episode, File},
+        relations::{RelationGraph, Related},
+        titles::{self, TitleMatch},
+        Anidb, Priority,
+    },
+    indexer::link,
 };
 
 pub mod mpv;
@@ -46,14 +56,15 @@ pub async fn all_anime() -> Result<Json<Vec<AnimeWithExtras>>> {
     let mut anime = sqlx::query!(
         "SELECT a.json, pl.*, wp.*
          FROM indexed_files if
-         INNER JOIN files f
+         LEFT OUTER JOIN files f
             ON if.fid = f.fid
          INNER JOIN anime a
-            ON f.aid = a.aid
+            ON coalesce(f.aid, if.linked_aid, iif(if.guessed_eid IS NULL, NULL, if.guessed_aid)) = a.aid
          INNER JOIN platform_links pl
             ON a.aid = pl.anidb_id
          LEFT OUTER JOIN watch_progress wp
             ON a.aid = wp.aid
+         WHERE if.missing_since IS NULL
          GROUP BY a.aid",
     )
     .fetch_all(db)
@@ -86,19 +97,129 @@ pub async fn all_anime() -> Result<Json<Vec<AnimeWithExtras>>> {
     Ok(Json(anime))
 }
 
+#[derive(Serialize)]
+pub struct AnimeWithDescription {
+    #[serde(flatten)]
+    anime: Anime,
+    description: Option<String>,
+}
+
 pub async fn anime(
     Path(aid): Path<u32>,
     State(state): State<Arc<RwLock<Anidb>>>,
-) -> Result<Json<Anime>> {
-    Ok(Json(
-        state
-            .write()
-            .await
-            .anime_by_aid(aid)
-            .await
-            .context("Couldn't fetch from AniDB")?
-            .context("Not found on AniDB")?,
-    ))
+) -> Result<Json<AnimeWithDescription>> {
+    let mut anidb = state.write().await;
+
+    let anime = anidb
+        .anime_by_aid(aid)
+        .await
+        .context("Couldn't fetch from AniDB")?
+        .context("Not found on AniDB")?;
+
+    // the synopsis is nice to have, don't fail the whole request over it
+    let description = anidb.anime_description(aid).await.unwrap_or_else(|e| {
+        log::warn!("Couldn't fetch description for anime {}: {}", aid, e);
+        None
+    });
+
+    Ok(Json(AnimeWithDescription { anime, description }))
+}
+
+#[derive(Serialize)]
+pub struct CharacterWithSeiyuu {
+    #[serde(flatten)]
+    character: Character,
+    seiyuu: Option<Creator>,
+}
+
+/// Anime whose characters are being fetched in the background
+static FETCHING_CHARACTERS: StdMutex<BTreeSet<u32>> = StdMutex::new(BTreeSet::new());
+
+/// The characters that are cached. A character and its seiyuu take two requests each, so
+/// missing ones are fetched in the background, to show up on a later visit.
+pub async fn anime_characters(
+    Path(aid): Path<u32>,
+    State(state): State<Arc<RwLock<Anidb>>>,
+) -> Result<Json<Vec<CharacterWithSeiyuu>>> {
+    let mut anidb = state.read().await.clone();
+
+    let anime = anidb
+        .anime_by_aid(aid)
+        .await
+        .context("Couldn't fetch from AniDB")?
+        .context("Not found on AniDB")?;
+
+    let mut characters = vec![];
+    let mut missing = vec![];
+
+    for &charid in &anime.characterid_list {
+        let Some(character) = anidb.cached_character(charid).await? else {
+            missing.push(charid);
+            continue;
+        };
+
+        let seiyuu = match seiyuu_id(&character, aid) {
+            Some(creatorid) => {
+                let seiyuu = anidb.cached_creator(creatorid).await?;
+                if seiyuu.is_none() {
+                    missing.push(charid);
+                }
+                seiyuu
+            }
+            None => None,
+        };
+
+        characters.push(CharacterWithSeiyuu { character, seiyuu });
+    }
+
+    if !missing.is_empty() && FETCHING_CHARACTERS.lock().unwrap().insert(aid) {
+        tokio::spawn(fetch_characters(anidb.with_priority(Priority::Bulk), aid, missing));
+    }
+
+    Ok(Json(characters))
+}
+
+fn seiyuu_id(character: &Character, aid: u32) -> Option<u32> {
+    character
+        .anime
+        .iter()
+        .find(|appearance| appearance.aid == aid)
+        .and_then(|appearance| appearance.creatorid)
+}
+
+async fn fetch_characters(mut anidb: Anidb, aid: u32, charids: Vec<u32>) {
+    for charid in charids {
+        let character = match anidb.character_by_id(charid).await {
+            Ok(Some(character)) => character,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("Couldn't fetch character {charid}: {e:#}");
+                break;
+            }
+        };
+
+        if let Some(creatorid) = seiyuu_id(&character, aid) {
+            if let Err(e) = anidb.creator_by_id(creatorid).await {
+                log::warn!("Couldn't fetch creator {creatorid}: {e:#}");
+                break;
+            }
+        }
+    }
+
+    FETCHING_CHARACTERS.lock().unwrap().remove(&aid);
+}
+
+pub async fn anime_related(
+    Path(aid): Path<u32>,
+    State(state): State<Arc<RwLock<Anidb>>>,
+) -> Result<Json<Vec<Related>>> {
+    let graph = RelationGraph::load(&mut *state.write().await, aid, |_| false)
+        .await
+        .context("Couldn't fetch from AniDB")?;
+
+    graph.get(aid).context("Not found on AniDB")?;
+
+    Ok(Json(graph.related(aid)))
 }
 
 pub async fn anime_episodes(Path(aid): Path<u32>) -> Result<Json<Vec<Episode>>> {
@@ -143,7 +264,7 @@ pub async fn anime_files(Path(aid): Path<u32>) -> Result<Json<Vec<WrappedFile>>>
          FROM indexed_files if
          INNER JOIN files f
             ON if.fid = f.fid
-         WHERE f.aid = ?",
+         WHERE f.aid = ? AND if.missing_since IS NULL",
         aid
     )
     .fetch_all(db)
@@ -158,11 +279,37 @@ pub async fn anime_files(Path(aid): Path<u32>) -> Result<Json<Vec<WrappedFile>>>
     })
     .collect::<anyhow::Result<Vec<WrappedFile>>>()?;
 
+    files.extend(
+        link::linked_files(aid)
+            .await?
+            .into_iter()
+            .map(|linked| WrappedFile { info: linked.file, path: linked.path }),
+    );
+
     files.sort_by(|a, b| a.path.cmp(&b.path));
 
     Ok(Json(files))
 }
 
+#[derive(Deserialize)]
+pub struct LinkFile {
+    path: String,
+    aid: u32,
+    epno: String,
+    gid: Option<u32>,
+}
+
+pub async fn link_file(
+    State(state): State<Arc<RwLock<Anidb>>>,
+    Json(LinkFile { path, aid, epno, gid }): Json<LinkFile>,
+) -> Result<Json<Episode>> {
+    let mut anidb = state.write().await;
+
+    let episode = link::link(&mut anidb, std::path::Path::new(&path), aid, &epno, gid).await?;
+
+    Ok(Json(episode))
+}
+
 #[derive(Deserialize)]
 pub struct ReportProgress {
     filepath: String,
@@ -175,7 +322,7 @@ pub async fn report_progress(
     let db = crate::DB.get().await;
 
     let row = sqlx::query!(
-        "SELECT a.aid, a.json as ajson, e.eid, e.json as ejson
+        "SELECT f.fid, a.aid, a.json as ajson, e.eid, e.json as ejson
          FROM indexed_files if
          INNER JOIN files f
             ON if.fid = f.fid
@@ -223,5 +370,23 @@ pub async fn report_progress(
     .execute(db)
     .await?;
 
+    let watched_percent = crate::CONFIG.read().await.anidb.watched_percent;
+
+    if progress * 100. >= watched_percent {
+        outbox::queue_watched(row.fid as u32, Utc::now()).await?;
+    }
+
     Ok(())
 }
+
+#[derive(Deserialize)]
+pub struct TitleSearch {
+    q: String,
+    limit: Option<u32>,
+}
+
+pub async fn search_titles(
+    Query(TitleSearch { q, limit }): Query<TitleSearch>,
+) -> Result<Json<Vec<TitleMatch>>> {
+    Ok(Json(titles::search(&q, limit.unwrap_or(20)).await?))
+}
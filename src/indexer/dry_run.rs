@@ -0,0 +1,166 @@
+//! Working out what an indexing run would do without doing any of it: nothing gets hashed,
+//! written to the database or sent to AniDB.
+
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+
+use super::{filter::FileFilter, reconcile, walk_filtered, IndexOptions};
+use crate::anidb::{RateLimiter, RateLimits};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Skip {
+    /// Left out by the include/exclude globs, the extension allowlist or for being hidden
+    Filtered,
+    /// Indexed, and the size and mtime haven't changed
+    Unchanged,
+    /// Indexed under another path that's gone now
+    Moved { from: PathBuf },
+}
+
+impl Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Filtered => write!(f, "filtered"),
+            Self::Unchanged => write!(f, "unchanged"),
+            Self::Moved { from } => write!(f, "moved from {}", from.display()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub hash: Vec<PathBuf>,
+    pub hash_bytes: u64,
+    pub skipped: Vec<(PathBuf, Skip)>,
+    /// AniDB queries the run needs at the very least. Anime, episodes and groups that
+    /// aren't cached yet come on top, but we can't know which those are before hashing.
+    pub queries: u32,
+    /// How long the rate limiter would take to let `queries` through
+    pub estimate: Duration,
+}
+
+pub async fn dry_run(path: &Path, options: &IndexOptions) -> Result<Plan> {
+    let config = crate::CONFIG.read().await;
+    let filter = FileFilter::new(path, &config.index, options)?;
+    let limits = RateLimits::from(&config.anidb);
+    drop(config);
+
+    let (files, filtered) = walk_filtered(path, &filter).await;
+
+    let mut plan = Plan {
+        skipped: filtered
+            .into_iter()
+            .map(|path| (path, Skip::Filtered))
+            .collect(),
+        ..Default::default()
+    };
+
+    for file in files {
+        let meta = file
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {}", file.display()))?;
+
+        match reconcile::Indexed::get(&file).await? {
+            Some(indexed) if indexed.unchanged(&meta) => {
+                if !options.verify {
+                    plan.skipped.push((file, Skip::Unchanged));
+                    continue;
+                }
+
+                // only asks AniDB if the hash turns out to have changed
+            }
+            Some(_) => plan.queries += 1,
+            None => match reconcile::find_moved(&meta, None).await? {
+                Some(from) => {
+                    let from = PathBuf::from(from);
+                    plan.skipped.push((file, Skip::Moved { from }));
+                    continue;
+                }
+                None => plan.queries += 1,
+            },
+        }
+
+        plan.hash_bytes += meta.len();
+        plan.hash.push(file);
+    }
+
+    if options.add_to_mylist {
+        plan.queries *= 2;
+    }
+
+    let limiter = RateLimiter::load(limits).await?;
+    plan.estimate = limiter.estimate(Utc::now(), plan.queries);
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn plans_without_writing() {
+        let db = crate::DB.get().await;
+        let dir = std::env::temp_dir().join(format!("tetsu-dry-run-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".thumbnails")).await.unwrap();
+
+        let new = dir.join("[TF] Seikai no Monshou - 01.mkv");
+        let unchanged = dir.join("[TF] Seikai no Monshou - 02.mkv");
+        for path in [&new, &unchanged] {
+            fs::write(path, b"a video file").await.unwrap();
+        }
+        fs::write(dir.join("[TF] Seikai no Monshou - 03.mkv.part"), b"half a video")
+            .await
+            .unwrap();
+        fs::write(dir.join("info.nfo"), b"notes").await.unwrap();
+
+        let utf_path = unchanged.to_string_lossy();
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, first_seen, last_updated)
+             VALUES (?, 'unchanged.mkv', 12, 'abcd', 0, 0)",
+            utf_path
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        let plan = dry_run(&dir, &IndexOptions::default()).await.unwrap();
+
+        assert_eq!(plan.hash, vec![new.clone()]);
+        assert_eq!(plan.hash_bytes, 12);
+        assert_eq!(plan.queries, 1);
+
+        let mut skipped = plan
+            .skipped
+            .iter()
+            .map(|(path, skip)| (path.file_name().unwrap().to_str().unwrap(), skip.clone()))
+            .collect::<Vec<_>>();
+        skipped.sort_by_key(|(name, _)| *name);
+        assert_eq!(
+            skipped,
+            [
+                (".thumbnails", Skip::Filtered),
+                ("[TF] Seikai no Monshou - 02.mkv", Skip::Unchanged),
+                ("[TF] Seikai no Monshou - 03.mkv.part", Skip::Filtered),
+                ("info.nfo", Skip::Filtered),
+            ]
+        );
+
+        let utf_path = new.to_string_lossy();
+        let rows = sqlx::query!("SELECT path FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_all(db)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
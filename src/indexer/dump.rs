@@ -1,117 +1,544 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt::{self, Display},
     fs,
+    io::{BufRead, BufReader, BufWriter, Write},
+    num::NonZeroU64,
     path::{Path, PathBuf},
 };
 
-use anyhow::Context as _;
-use serde::{Deserialize, Serialize};
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{
-    anidb::records::{Anime, Episode, File, Group},
-    ANIDB,
-};
+use crate::anidb::records::{Anime, Episode, File, Group};
+
+/// Bumped whenever a change to the dump would trip up older versions of tetsu reading it
+pub const VERSION: u32 = 2;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataDump {
+    /// Dumps from before there was a version are version 1
+    #[serde(default = "first_version")]
+    pub version: u32,
     pub files: HashMap<PathBuf, File>,
     pub anime: Vec<Anime>,
     pub episodes: Vec<Episode>,
     pub groups: Vec<Group>,
+    #[serde(default)]
+    pub watch_progress: Vec<WatchProgress>,
+    #[serde(default)]
+    pub platform_links: Vec<PlatformLinks>,
+}
+
+fn first_version() -> u32 {
+    1
 }
 
-pub async fn dump_json(folder: &Path, dump_path: &Path) -> anyhow::Result<()> {
-    let mut dirs = vec![folder.to_owned()];
-    let mut paths = vec![];
+impl Default for DataDump {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            files: HashMap::new(),
+            anime: vec![],
+            episodes: vec![],
+            groups: vec![],
+            watch_progress: vec![],
+            platform_links: vec![],
+        }
+    }
+}
 
-    while let Some(dir) = dirs.pop() {
-        let rd = fs::read_dir(dir).unwrap();
-        for entry in rd.map_while(Result::ok) {
-            let path = entry.path();
-            if path.is_dir() {
-                dirs.push(path);
-            } else {
-                paths.push(path);
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchProgress {
+    pub aid: u32,
+    pub last_eid: u32,
+    pub episode_progress: f64,
+    pub anime_progress: f64,
+    pub last_updated: DateTime<Utc>,
+}
+
+/// The same anime on other sites
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlatformLinks {
+    pub animebytes_id: Option<NonZeroU64>,
+    pub anidb_id: Option<NonZeroU64>,
+    pub ann_id: Option<NonZeroU64>,
+    pub anilist_id: Option<NonZeroU64>,
+    pub mal_id: Option<NonZeroU64>,
+}
+
+/// A line of an NDJSON dump. The first one is always the header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Entry {
+    Header { version: u32 },
+    File { path: PathBuf, file: File },
+    Anime(Anime),
+    Episode(Episode),
+    Group(Group),
+    WatchProgress(WatchProgress),
+    PlatformLinks(PlatformLinks),
+}
+
+impl DataDump {
+    fn push(&mut self, entry: Entry) {
+        match entry {
+            Entry::Header { version } => self.version = version,
+            Entry::File { path, file } => {
+                self.files.insert(path, file);
             }
+            Entry::Anime(anime) => self.anime.push(anime),
+            Entry::Episode(episode) => self.episodes.push(episode),
+            Entry::Group(group) => self.groups.push(group),
+            Entry::WatchProgress(progress) => self.watch_progress.push(progress),
+            Entry::PlatformLinks(links) => self.platform_links.push(links),
+        }
+    }
+}
+
+/// What to dump
+#[derive(Debug, Clone, Copy)]
+pub enum Scope<'a> {
+    /// The indexed files below a folder and what they refer to
+    Folder(&'a Path),
+    /// Every indexed file and everything cached
+    Library,
+}
+
+/// Something that kept a record out of the dump
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpError {
+    /// Indexed, but not identified by AniDB
+    Unidentified { path: PathBuf },
+    /// Referred to by a dumped record, but not cached
+    Missing { record: &'static str, id: u32 },
+    /// Cached, but not readable by this version of tetsu
+    Invalid {
+        record: &'static str,
+        id: u32,
+        error: String,
+    },
+}
+
+impl Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unidentified { path } => write!(f, "{} isn't identified", path.display()),
+            Self::Missing { record, id } => write!(f, "{record} {id} isn't cached"),
+            Self::Invalid { record, id, error } => write!(f, "{record} {id} is invalid: {error}"),
         }
     }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files: usize,
+    pub anime: usize,
+    pub episodes: usize,
+    pub groups: usize,
+    pub watch_progress: usize,
+    pub platform_links: usize,
+    pub errors: Vec<DumpError>,
+}
+
+enum Output {
+    Json(DataDump),
+    Ndjson(BufWriter<fs::File>),
+}
+
+impl Output {
+    fn push(&mut self, entry: Entry) -> Result<()> {
+        match self {
+            Self::Json(dump) => dump.push(entry),
+            Self::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &entry)?;
+                writer.write_all(b"\n")?;
+            }
+        }
 
+        Ok(())
+    }
+}
+
+/// Write a dump to `dump_path`, with file paths relative to the directory it's in. NDJSON
+/// dumps are written as records are read instead of being put together in memory first.
+pub async fn dump_json(scope: Scope<'_>, dump_path: &Path, ndjson: bool) -> Result<Summary> {
     let base = dump_path
         .parent()
         .map(Path::to_path_buf)
         .unwrap_or_default();
 
+    let file = fs::File::create(dump_path)
+        .with_context(|| format!("Failed to create {}", dump_path.display()))?;
+
+    let written = write_dump(scope, &base, file, ndjson).await;
+    if written.is_err() {
+        // don't leave a half written dump around to be imported later
+        let _ = fs::remove_file(dump_path);
+    }
+
+    written
+}
+
+async fn write_dump(
+    scope: Scope<'_>,
+    base: &Path,
+    file: fs::File,
+    ndjson: bool,
+) -> Result<Summary> {
+    if ndjson {
+        let mut output = Output::Ndjson(BufWriter::new(file));
+        let summary = write_entries(scope, Some(base), &mut output).await?;
+
+        if let Output::Ndjson(mut writer) = output {
+            writer.flush().context("Failed to write NDJSON dump")?;
+        }
+
+        return Ok(summary);
+    }
+
+    let mut output = Output::Json(DataDump::default());
+    let summary = write_entries(scope, Some(base), &mut output).await?;
+
+    if let Output::Json(dump) = output {
+        serde_json::to_writer_pretty(BufWriter::new(file), &dump)
+            .context("Failed to write JSON dump")?;
+    }
+
+    Ok(summary)
+}
+
+/// AniDB data for the identified files in `folder`, keyed by the path they were found at
+pub async fn collect(folder: &Path) -> Result<DataDump> {
+    let mut output = Output::Json(DataDump::default());
+    let summary = write_entries(Scope::Folder(folder), None, &mut output).await?;
+
+    for error in &summary.errors {
+        log::warn!("{error}");
+    }
+
+    match output {
+        Output::Json(dump) => Ok(dump),
+        Output::Ndjson(_) => unreachable!(),
+    }
+}
+
+/// Read a JSON or NDJSON dump. NDJSON is read a line at a time, JSON as a whole.
+pub fn read(path: &Path) -> Result<DataDump> {
+    let open =
+        || fs::File::open(path).with_context(|| format!("Failed to read {}", path.display()));
+
+    let mut lines = BufReader::new(open()?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()));
+
+    // only NDJSON dumps have a line of their own for the header
+    let header = match lines.next() {
+        Some((_, line)) => serde_json::from_str::<Entry>(&line?).ok(),
+        None => None,
+    };
+
+    let dump = match header {
+        Some(Entry::Header { version }) => {
+            check_version(version)?;
+
+            let mut dump = DataDump::default();
+            for (i, line) in lines {
+                let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+                let entry = serde_json::from_str(&line)
+                    .with_context(|| format!("Invalid entry on line {}", i + 1))?;
+                dump.push(entry);
+            }
+
+            dump
+        }
+        _ => serde_json::from_reader::<_, DataDump>(BufReader::new(open()?))
+            .context("Invalid data dump")?,
+    };
+
+    check_version(dump.version)?;
+
+    Ok(dump)
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version > VERSION {
+        anyhow::bail!("The dump is version {version}, this version of tetsu reads up to {VERSION}");
+    }
+
+    Ok(())
+}
+
+/// Records are read straight from the cache, nothing is fetched from AniDB
+async fn write_entries(scope: Scope<'_>, base: Option<&Path>, out: &mut Output) -> Result<Summary> {
+    let db = crate::DB.get().await;
+    let mut summary = Summary::default();
+    let all = matches!(scope, Scope::Library);
+
     let mut aids = HashSet::new();
     let mut eids = HashSet::new();
     let mut gids = HashSet::new();
-    let mut files = HashMap::new();
-
-    for path in paths {
-        let path_str = path.to_string_lossy();
-
-        let Some(fid) = sqlx::query!("SELECT fid FROM indexed_files WHERE path = $1", path_str)
-            .fetch_optional(crate::DB.get().await)
-            .await?
-            .and_then(|r| r.fid)
-            .and_then(|s| s.try_into().ok())
-        else {
-            eprintln!("File can't be identified: {}", path_str);
+
+    out.push(Entry::Header { version: VERSION })?;
+
+    let mut rows = sqlx::query!(
+        r#"SELECT if.path, if.fid, f.json AS "json?"
+         FROM indexed_files if
+         LEFT OUTER JOIN files f
+            ON if.fid = f.fid
+         WHERE if.missing_since IS NULL
+         ORDER BY if.path"#
+    )
+    .fetch(db);
+
+    while let Some(row) = rows.try_next().await? {
+        let path = PathBuf::from(row.path);
+
+        if let Scope::Folder(folder) = scope {
+            if !path.starts_with(folder) {
+                continue;
+            }
+        }
+
+        let Some(fid) = row.fid else {
+            summary.errors.push(DumpError::Unidentified { path });
             continue;
         };
 
-        let mut anidb = ANIDB.write().await;
-
-        let Some(file) = anidb.file_by_fid(fid).await? else {
+        let Some(json) = row.json else {
+            let id = fid as u32;
+            summary
+                .errors
+                .push(DumpError::Missing { record: "file", id });
             continue;
         };
 
+        let file = match serde_json::from_str::<File>(&json) {
+            Ok(file) => file,
+            Err(e) => {
+                let (id, error) = (fid as u32, e.to_string());
+                summary
+                    .errors
+                    .push(DumpError::Invalid { record: "file", id, error });
+                continue;
+            }
+        };
+
         aids.insert(file.aid);
         eids.insert(file.eid);
-        gids.insert(file.gid);
+        if file.gid != 0 {
+            gids.insert(file.gid);
+        }
+
+        let path = match base {
+            Some(base) => {
+                let path = path.strip_prefix(base).unwrap_or(&path);
+                path.strip_prefix(".").unwrap_or(path).to_owned()
+            }
+            None => path,
+        };
 
-        let path = path.strip_prefix(&base).unwrap_or(&path).to_owned();
-        let path = path.strip_prefix(".").unwrap_or(&path).to_owned();
-        files.insert(path, file);
+        out.push(Entry::File { path, file })?;
+        summary.files += 1;
     }
 
-    if files.is_empty() {
+    drop(rows);
+
+    if matches!(scope, Scope::Folder(_)) && summary.files == 0 {
         anyhow::bail!("No indexed files found in the specified folder");
     }
 
-    let mut anime = vec![];
-    for aid in aids {
-        let mut anidb = ANIDB.write().await;
-        if let Some(a) = anidb.anime_by_aid(aid).await? {
-            anime.push(a);
-        } else {
-            eprintln!("Anime not found for AID: {}", aid);
+    let rows = sqlx::query_as!(Cached, r#"SELECT aid AS "id!", json FROM anime"#).fetch(db);
+    summary.anime =
+        push_records(out, &mut summary.errors, rows, "anime", &aids, all, Entry::Anime).await?;
+
+    let rows = sqlx::query_as!(Cached, r#"SELECT eid AS "id!", json FROM episodes"#).fetch(db);
+    summary.episodes =
+        push_records(out, &mut summary.errors, rows, "episode", &eids, all, Entry::Episode).await?;
+
+    let rows = sqlx::query_as!(Cached, r#"SELECT gid AS "id!", json FROM groups"#).fetch(db);
+    summary.groups =
+        push_records(out, &mut summary.errors, rows, "group", &gids, all, Entry::Group).await?;
+
+    let mut rows = sqlx::query!("SELECT * FROM watch_progress").fetch(db);
+    while let Some(row) = rows.try_next().await? {
+        let aid = row.aid as u32;
+        if !all && !aids.contains(&aid) {
+            continue;
         }
+
+        out.push(Entry::WatchProgress(WatchProgress {
+            aid,
+            last_eid: row.last_eid as u32,
+            episode_progress: row.episode_progress,
+            anime_progress: row.anime_progress,
+            last_updated: DateTime::from_timestamp(row.last_updated, 0).unwrap_or_default(),
+        }))?;
+        summary.watch_progress += 1;
     }
 
-    let mut episodes = vec![];
-    for eid in eids {
-        let mut anidb = ANIDB.write().await;
-        if let Some(e) = anidb.episode_by_eid(eid).await? {
-            episodes.push(e);
-        } else {
-            eprintln!("Episode not found for EID: {}", eid);
+    drop(rows);
+
+    let mut rows = sqlx::query!("SELECT * FROM platform_links").fetch(db);
+    while let Some(row) = rows.try_next().await? {
+        if !all && !aids.contains(&(row.anidb_id as u32)) {
+            continue;
         }
+
+        out.push(Entry::PlatformLinks(PlatformLinks {
+            animebytes_id: NonZeroU64::new(row.animebytes_id as u64),
+            anidb_id: NonZeroU64::new(row.anidb_id as u64),
+            ann_id: NonZeroU64::new(row.ann_id as u64),
+            anilist_id: NonZeroU64::new(row.anilist_id as u64),
+            mal_id: NonZeroU64::new(row.mal_id as u64),
+        }))?;
+        summary.platform_links += 1;
     }
 
-    let mut groups = vec![];
-    for gid in gids {
-        let mut anidb = ANIDB.write().await;
-        if let Some(g) = anidb.group_by_gid(gid).await? {
-            groups.push(g);
-        } else {
-            eprintln!("Group not found for GID: {}", gid);
+    Ok(summary)
+}
+
+/// A row of one of the JSON record caches
+struct Cached {
+    id: i64,
+    json: String,
+}
+
+/// Dump the `referenced` records, or all of them with `all`, and report the referenced
+/// ones that aren't there
+async fn push_records<R: DeserializeOwned>(
+    out: &mut Output,
+    errors: &mut Vec<DumpError>,
+    mut rows: BoxStream<'_, sqlx::Result<Cached>>,
+    record: &'static str,
+    referenced: &HashSet<u32>,
+    all: bool,
+    entry: fn(R) -> Entry,
+) -> Result<usize> {
+    let mut found = HashSet::new();
+    let mut count = 0;
+
+    while let Some(row) = rows.try_next().await? {
+        let id = row.id as u32;
+        if !all && !referenced.contains(&id) {
+            continue;
+        }
+
+        found.insert(id);
+
+        match serde_json::from_str(&row.json) {
+            Ok(value) => {
+                out.push(entry(value))?;
+                count += 1;
+            }
+            Err(e) => errors.push(DumpError::Invalid { record, id, error: e.to_string() }),
         }
     }
 
-    let dump = DataDump { files, anime, episodes, groups };
+    let mut missing = referenced.difference(&found).copied().collect::<Vec<_>>();
+    missing.sort_unstable();
+    errors.extend(
+        missing
+            .into_iter()
+            .map(|id| DumpError::Missing { record, id }),
+    );
+
+    Ok(count)
+}
 
-    let mut file = fs::File::create(dump_path)?;
-    serde_json::to_writer_pretty(&mut file, &dump).context("Failed to write JSON dump")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::{mock::fixtures, records::Record};
 
-    Ok(())
+    #[tokio::test]
+    async fn dumps_ndjson() {
+        let db = crate::DB.get().await;
+        let dir = std::env::temp_dir().join(format!("tetsu-dump-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut file = File::parse(&fixtures::file(500, 24, "abcd")).unwrap();
+        (file.aid, file.eid, file.gid) = (500, 500, 0);
+        let mut anime = Anime::parse(fixtures::ANIME).unwrap();
+        anime.aid = 500;
+
+        let identified = dir.join("01.mkv").to_string_lossy().to_string();
+        let unidentified = dir.join("02.mkv").to_string_lossy().to_string();
+        let (file_json, anime_json) =
+            (serde_json::to_string(&file).unwrap(), serde_json::to_string(&anime).unwrap());
+
+        sqlx::query!(
+            "INSERT INTO files (fid, aid, eid, gid, size, ed2k, json)
+             VALUES (500, 500, 500, 0, 24, 'abcd', ?)",
+            file_json
+        )
+        .execute(db)
+        .await
+        .unwrap();
+        sqlx::query!("INSERT INTO anime (aid, json) VALUES (500, ?)", anime_json)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO watch_progress (aid, last_eid, episode_progress, anime_progress, last_updated)
+             VALUES (500, 500, 0.5, 0.25, 946684800)"
+        )
+        .execute(db)
+        .await
+        .unwrap();
+        sqlx::query!("INSERT INTO platform_links (anidb_id, mal_id) VALUES (500, 1500)")
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, fid, first_seen, last_updated)
+             VALUES (?, '01.mkv', 24, 'abcd', 500, 0, 0), (?, '02.mkv', 24, 'efgh', NULL, 0, 0)",
+            identified,
+            unidentified
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        let dump_path = dir.join("dump.ndjson");
+        let summary = dump_json(Scope::Folder(&dir), &dump_path, true)
+            .await
+            .unwrap();
+
+        assert_eq!((summary.files, summary.anime, summary.episodes, summary.groups), (1, 1, 0, 0));
+        assert_eq!((summary.watch_progress, summary.platform_links), (1, 1));
+        assert_eq!(
+            summary.errors,
+            [
+                DumpError::Unidentified { path: dir.join("02.mkv") },
+                DumpError::Missing { record: "episode", id: 500 },
+            ]
+        );
+
+        let dump = read(&dump_path).unwrap();
+        assert_eq!(dump.version, VERSION);
+        assert_eq!(dump.files[Path::new("01.mkv")], file);
+        assert_eq!(dump.anime, [anime]);
+        assert_eq!(dump.watch_progress[0].episode_progress, 0.5);
+        assert_eq!(dump.platform_links[0].mal_id, NonZeroU64::new(1500));
+
+        // a folder without identified files is an error, and leaves no dump behind
+        let empty = dir.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        let empty_dump = dir.join("empty.ndjson");
+        assert!(dump_json(Scope::Folder(&empty), &empty_dump, true)
+            .await
+            .is_err());
+        assert!(!empty_dump.exists());
+
+        // dumps from before the version and the extra tables still load
+        let legacy = dir.join("legacy.json");
+        fs::write(&legacy, r#"{"files": {}, "anime": [], "episodes": [], "groups": []}"#).unwrap();
+        assert_eq!(read(&legacy).unwrap().version, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
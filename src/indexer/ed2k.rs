@@ -1,27 +1,197 @@
-use std::{fs::File, path::Path};
+use std::{
+    fs::File,
+    io::{ErrorKind, Read},
+    path::Path,
+};
 
 use anyhow::{Context, Result};
 use indicatif::ProgressBar;
 use md4::{Digest, Md4};
+use md5::Md5;
 use memmap::Mmap;
 use rayon::{prelude::ParallelIterator, slice::ParallelSlice};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 
 const ED2K_CHUNK_SIZE: usize = 9728000;
 
+/// How file contents get to the hasher. Mapping is fastest on local disks, but page faults
+/// over a network mount are slow enough that plain sequential reads win there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Hasher {
+    #[default]
+    Mmap,
+    Read,
+}
+
+impl Hasher {
+    pub fn hash_file<P: AsRef<Path>>(self, file: P, pb: &ProgressBar) -> Result<String> {
+        Ok(self.hash_file_with(file, pb, false)?.ed2k)
+    }
+
+    /// The ed2k hash, plus CRC32, MD5 and SHA1 if `extra` is set, all in one pass
+    pub fn hash_file_with<P: AsRef<Path>>(
+        self,
+        file: P,
+        pb: &ProgressBar,
+        extra: bool,
+    ) -> Result<Hashes> {
+        match self {
+            Self::Mmap => hash_mapped(file, pb, extra),
+            Self::Read => hash_read(file, pb, extra),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hashes {
+    pub ed2k: String,
+    pub crc32: Option<String>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+}
+
+/// The hashes that, unlike ed2k, need to see the whole file in order
+#[derive(Default)]
+struct Sequential {
+    crc32: crc32fast::Hasher,
+    md5: Md5,
+    sha1: Sha1,
+}
+
+impl Sequential {
+    fn update(&mut self, data: &[u8]) {
+        self.crc32.update(data);
+        self.md5.update(data);
+        self.sha1.update(data);
+    }
+
+    fn finish(self, hashes: &mut Hashes) {
+        hashes.crc32 = Some(format!("{:08x}", self.crc32.finalize()));
+        hashes.md5 = Some(format!("{:x}", self.md5.finalize()));
+        hashes.sha1 = Some(format!("{:x}", self.sha1.finalize()));
+    }
+}
+
 pub fn hash_file<P: AsRef<Path>>(file: P, pb: &ProgressBar) -> Result<String> {
+    Ok(hash_mapped(file, pb, false)?.ed2k)
+}
+
+fn hash_mapped<P: AsRef<Path>>(file: P, pb: &ProgressBar, extra: bool) -> Result<Hashes> {
     let file = File::open(file).context("Failed to open file")?;
     let map = unsafe { Mmap::map(&file) }.context("Failed to map file into memory")?;
 
     pb.set_length(map.len() as u64 / ED2K_CHUNK_SIZE as u64);
 
-    let hashes: Vec<[u8; 16]> = map
-        .par_chunks(ED2K_CHUNK_SIZE)
-        .map(Md4::digest)
-        .map(Into::into)
-        .inspect(|_| pb.inc(1))
-        .collect();
+    // ed2k chunks are hashed in parallel, the rest has to go through the map in order
+    let (hashes, sequential) = rayon::join(
+        || -> Vec<[u8; 16]> {
+            map.par_chunks(ED2K_CHUNK_SIZE)
+                .map(Md4::digest)
+                .map(Into::into)
+                .inspect(|_| pb.inc(1))
+                .collect()
+        },
+        || {
+            extra.then(|| {
+                let mut sequential = Sequential::default();
+                sequential.update(&map);
+                sequential
+            })
+        },
+    );
 
     let root_hash = Md4::digest(hashes.concat());
 
-    Ok(format!("{root_hash:032x}"))
+    let mut hashes = Hashes {
+        ed2k: format!("{root_hash:032x}"),
+        ..Default::default()
+    };
+
+    if let Some(sequential) = sequential {
+        sequential.finish(&mut hashes);
+    }
+
+    Ok(hashes)
+}
+
+fn hash_read<P: AsRef<Path>>(file: P, pb: &ProgressBar, extra: bool) -> Result<Hashes> {
+    let mut file = File::open(file).context("Failed to open file")?;
+    let len = file
+        .metadata()
+        .context("Failed to read file metadata")?
+        .len();
+
+    pb.set_length(len / ED2K_CHUNK_SIZE as u64);
+
+    let mut chunk = vec![0; ED2K_CHUNK_SIZE];
+    let mut hashes = vec![];
+    let mut sequential = extra.then(Sequential::default);
+
+    loop {
+        let mut filled = 0;
+        while filled < chunk.len() {
+            match file.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to read file"),
+            }
+        }
+
+        if filled == 0 {
+            break;
+        }
+
+        hashes.extend_from_slice(&Md4::digest(&chunk[..filled]));
+        if let Some(ref mut sequential) = sequential {
+            sequential.update(&chunk[..filled]);
+        }
+        pb.inc(1);
+
+        if filled < chunk.len() {
+            break;
+        }
+    }
+
+    let root_hash = Md4::digest(hashes);
+
+    let mut hashes = Hashes {
+        ed2k: format!("{root_hash:032x}"),
+        ..Default::default()
+    };
+
+    if let Some(sequential) = sequential {
+        sequential.finish(&mut hashes);
+    }
+
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashers_agree() {
+        let path = std::env::temp_dir().join(format!("tetsu-ed2k-{}", std::process::id()));
+        let contents = (0..ED2K_CHUNK_SIZE * 2 + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&path, contents).unwrap();
+
+        let pb = ProgressBar::hidden();
+        let mapped = Hasher::Mmap.hash_file_with(&path, &pb, true).unwrap();
+        assert_eq!(mapped, Hasher::Read.hash_file_with(&path, &pb, true).unwrap());
+        assert_eq!(mapped.ed2k, Hasher::Read.hash_file(&path, &pb).unwrap());
+
+        std::fs::write(&path, b"The quick brown fox jumps over the lazy dog").unwrap();
+        let hashes = Hasher::Read.hash_file_with(&path, &pb, true).unwrap();
+        assert_eq!(hashes.crc32.unwrap(), "414fa339");
+        assert_eq!(hashes.md5.unwrap(), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(hashes.sha1.unwrap(), "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
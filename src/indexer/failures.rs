@@ -0,0 +1,78 @@
+//! Files an indexing run gave up on. One bad file or AniDB response shouldn't cost a
+//! multi-hour run, so failures are written down here and `--retry-failed` picks them up.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+pub async fn record(path: &Path, reason: &anyhow::Error) -> Result<()> {
+    let utf_path = path.to_string_lossy();
+    let reason = format!("{reason:#}");
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query!(
+        "INSERT INTO index_failures (path, reason, failed_at) VALUES (?, ?, ?)
+         ON CONFLICT (path) DO UPDATE
+         SET reason = excluded.reason, attempts = attempts + 1, failed_at = excluded.failed_at",
+        utf_path,
+        reason,
+        now
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(())
+}
+
+/// Forget a failure once the file made it through
+pub async fn clear(path: &Path) -> Result<()> {
+    let utf_path = path.to_string_lossy();
+
+    sqlx::query!("DELETE FROM index_failures WHERE path = ?", utf_path)
+        .execute(crate::DB.get().await)
+        .await?;
+
+    Ok(())
+}
+
+/// Failed files at or below `path`, or all of them
+pub async fn failed(path: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let paths = sqlx::query_scalar!("SELECT path FROM index_failures ORDER BY path")
+        .fetch_all(crate::DB.get().await)
+        .await?;
+
+    Ok(paths
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|failed| path.is_none_or(|path| failed.starts_with(path)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn records_and_clears_failures() {
+        let dir = std::env::temp_dir().join(format!("tetsu-failures-{}", std::process::id()));
+        let path = dir.join("a.mkv");
+
+        record(&path, &anyhow!("timed out")).await.unwrap();
+        record(&path, &anyhow!("no such file")).await.unwrap();
+        assert_eq!(failed(Some(&dir)).await.unwrap(), vec![path.clone()]);
+        assert!(failed(Some(&dir.join("other"))).await.unwrap().is_empty());
+
+        let utf_path = path.to_string_lossy();
+        let row =
+            sqlx::query!("SELECT reason, attempts FROM index_failures WHERE path = ?", utf_path)
+                .fetch_one(crate::DB.get().await)
+                .await
+                .unwrap();
+        assert_eq!((row.reason.as_str(), row.attempts), ("no such file", 2));
+
+        clear(&path).await.unwrap();
+        assert!(failed(Some(&dir)).await.unwrap().is_empty());
+    }
+}
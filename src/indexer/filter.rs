@@ -0,0 +1,155 @@
+//! Deciding which files are worth hashing. Every file we hash costs an AniDB FILE query,
+//! so subtitles, thumbnails and half-finished downloads are better left alone.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use super::IndexOptions;
+use crate::config::IndexConfig;
+
+/// Filters files below `root`, globs are matched against paths relative to it
+pub struct FileFilter {
+    root: PathBuf,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// Lowercase, empty to allow any extension
+    extensions: Vec<String>,
+    skip_hidden: bool,
+}
+
+impl FileFilter {
+    /// The filter from the config, with the overrides from `options`. Included globs and
+    /// extensions from the command line replace the configured ones, excluded globs are
+    /// added to them.
+    pub fn new(root: &Path, config: &IndexConfig, options: &IndexOptions) -> Result<Self> {
+        let include = options.include.as_ref().unwrap_or(&config.include);
+        let exclude = config.exclude.iter().chain(&options.exclude);
+        let extensions = options.extensions.as_ref().unwrap_or(&config.extensions);
+
+        Ok(Self {
+            root: root.to_owned(),
+            include: (!include.is_empty())
+                .then(|| glob_set(include))
+                .transpose()?,
+            exclude: glob_set(exclude)?,
+            extensions: extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect(),
+            skip_hidden: config.skip_hidden,
+        })
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    fn excluded(&self, path: &Path) -> bool {
+        self.skip_hidden && hidden(path) || self.exclude.is_match(self.relative(path))
+    }
+
+    pub fn allows_dir(&self, path: &Path) -> bool {
+        !self.excluded(path)
+    }
+
+    pub fn allows_file(&self, path: &Path) -> bool {
+        if self.excluded(path) {
+            return false;
+        }
+
+        if !self.extensions.is_empty() {
+            let extension = path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+
+            if !extension.is_some_and(|ext| self.extensions.contains(&ext)) {
+                return false;
+            }
+        }
+
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(self.relative(path)))
+    }
+
+    /// Whether walking the root would get into `dir`
+    pub fn reaches(&self, dir: &Path) -> bool {
+        let mut path = self.root.clone();
+
+        self.relative(dir).components().all(|component| {
+            path.push(component);
+            self.allows_dir(&path)
+        })
+    }
+
+    /// Whether walking the root would find `path`
+    pub fn allows(&self, path: &Path) -> bool {
+        path.parent().is_none_or(|dir| self.reaches(dir)) && self.allows_file(path)
+    }
+}
+
+/// Globs without a slash match the file name anywhere, like in a .gitignore
+fn glob_set<'a>(patterns: impl IntoIterator<Item = &'a String>) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = if pattern.contains('/') {
+            pattern.trim_start_matches('/').to_string()
+        } else {
+            format!("**/{pattern}")
+        };
+
+        let glob = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob: {pattern}"))?;
+
+        builder.add(glob);
+    }
+
+    builder.build().context("Failed to build glob set")
+}
+
+fn hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_files() {
+        let root = Path::new("/anime");
+        let filter =
+            FileFilter::new(root, &IndexConfig::default(), &IndexOptions::default()).unwrap();
+        let allowed = |path: &str| filter.allows(&root.join(path));
+
+        assert!(allowed("Seikai no Monshou/[TF] Seikai no Monshou - 01.mkv"));
+        assert!(allowed("Seikai no Monshou/[TF] Seikai no Monshou - 02.MP4"));
+        assert!(!allowed("Seikai no Monshou/[TF] Seikai no Monshou - 01.ass"));
+        assert!(!allowed("Seikai no Monshou/info.nfo"));
+        assert!(!allowed("Seikai no Monshou/[TF] Seikai no Monshou - 03.mkv.part"));
+        assert!(!allowed("Seikai no Monshou/[TF] Seikai no Monshou - 03.mkv.!qB"));
+        assert!(!allowed(".thumbnails/[TF] Seikai no Monshou - 01.mkv"));
+        assert!(!allowed("Seikai no Monshou/.[TF] Seikai no Monshou - 01.mkv"));
+
+        let options = IndexOptions {
+            include: Some(vec!["Seikai*/**".to_string()]),
+            exclude: vec!["*NCOP*".to_string()],
+            extensions: Some(vec![".ass".to_string()]),
+            ..Default::default()
+        };
+        let filter = FileFilter::new(root, &IndexConfig::default(), &options).unwrap();
+        let allowed = |path: &str| filter.allows(&root.join(path));
+
+        assert!(allowed("Seikai no Monshou/[TF] Seikai no Monshou - 01.ass"));
+        assert!(!allowed("Seikai no Monshou/[TF] Seikai no Monshou - 01.mkv"));
+        assert!(!allowed("Seikai no Monshou/[TF] Seikai no Monshou - NCOP.ass"));
+        assert!(!allowed("Banner of the Stars/[TF] Banner of the Stars - 01.ass"));
+    }
+}
@@ -0,0 +1,394 @@
+//! Loading a [`DataDump`] written by `tetsu export dump` on another machine, so a
+//! copy of the same library can be set up without asking AniDB about every file again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroU64,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use indicatif::ProgressBar;
+
+use crate::anidb::titles;
+
+use super::{
+    dump::{self, DataDump},
+    filter::FileFilter,
+    walk, IndexOptions,
+};
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub anime: usize,
+    pub episodes: usize,
+    pub groups: usize,
+    pub watch_progress: usize,
+    pub platform_links: usize,
+    /// Files from the dump found below the root
+    pub matched: usize,
+    /// Matched files found by name alone, which the next scan hashes
+    pub unverified: usize,
+    /// Paths from the dump, as written in it, that nothing below the root matched
+    pub unmatched: Vec<PathBuf>,
+}
+
+/// Cache the records in the dump and index the files below `root` they describe. Files are
+/// matched by their path relative to the root and their size, falling back to the same
+/// name and size anywhere below it. The hash of those isn't trusted until the next scan
+/// checks it. With `rehash` the ed2k has to match instead, which also finds renamed files.
+pub async fn import_dump(dump_path: &Path, root: &Path, rehash: bool) -> Result<Report> {
+    let dump = {
+        let dump_path = dump_path.to_owned();
+        tokio::task::spawn_blocking(move || dump::read(&dump_path)).await??
+    };
+
+    let mut report = Report {
+        anime: dump.anime.len(),
+        episodes: dump.episodes.len(),
+        groups: dump.groups.len(),
+        watch_progress: dump.watch_progress.len(),
+        platform_links: dump.platform_links.len(),
+        ..Default::default()
+    };
+
+    cache_records(&dump).await?;
+
+    let config = crate::CONFIG.read().await.index.clone();
+    let filter = FileFilter::new(root, &config, &IndexOptions::default())?;
+
+    let mut by_size = HashMap::<u64, Vec<PathBuf>>::new();
+    for path in walk(root, &filter).await {
+        if let Ok(meta) = path.metadata() {
+            by_size.entry(meta.len()).or_default().push(path);
+        }
+    }
+
+    let mut claimed = HashSet::new();
+    let mut hashed = HashMap::<PathBuf, String>::new();
+
+    for (dump_path, file) in &dump.files {
+        let expected = root.join(dump_path);
+        let size = file.size as u64;
+
+        let mut candidates = by_size
+            .get(&size)
+            .into_iter()
+            .flatten()
+            .filter(|path| !claimed.contains(*path))
+            .collect::<Vec<_>>();
+
+        // the path from the dump first, then the ones with the same name
+        candidates
+            .sort_by_key(|path| (**path != expected, path.file_name() != dump_path.file_name()));
+
+        let mut found = None;
+
+        for candidate in candidates {
+            let matches = if rehash {
+                // files are candidates for every dump entry of their size
+                let hash = match hashed.get(candidate) {
+                    Some(hash) => hash.clone(),
+                    None => {
+                        let (path, hasher) = (candidate.clone(), config.hasher);
+                        let hash = tokio::task::spawn_blocking(move || {
+                            hasher.hash_file(&path, &ProgressBar::hidden())
+                        })
+                        .await??;
+
+                        hashed.insert(candidate.clone(), hash.clone());
+                        hash
+                    }
+                };
+
+                hash.eq_ignore_ascii_case(&file.ed2k)
+            } else {
+                *candidate == expected || candidate.file_name() == dump_path.file_name()
+            };
+
+            if matches {
+                found = Some((candidate.clone(), rehash || *candidate == expected));
+                break;
+            }
+        }
+
+        let Some((path, verified)) = found else {
+            report.unmatched.push(dump_path.clone());
+            continue;
+        };
+
+        index_file(&path, file, verified).await?;
+        claimed.insert(path);
+        report.matched += 1;
+        if !verified {
+            report.unverified += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+async fn cache_records(dump: &DataDump) -> Result<()> {
+    let mut tx = crate::DB.get().await.begin().await?;
+
+    for anime in &dump.anime {
+        let json = serde_json::to_string(anime)?;
+
+        sqlx::query!(
+            "INSERT INTO anime (aid, json) VALUES ($1, $2)
+             ON CONFLICT (aid) DO UPDATE SET json = $2",
+            anime.aid,
+            json,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for episode in &dump.episodes {
+        let json = serde_json::to_string(episode)?;
+
+        sqlx::query!(
+            "INSERT INTO episodes (eid, aid, json) VALUES ($1, $2, $3)
+             ON CONFLICT (eid) DO UPDATE SET aid = $2, json = $3",
+            episode.eid,
+            episode.aid,
+            json,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for group in &dump.groups {
+        let json = serde_json::to_string(group)?;
+
+        sqlx::query!(
+            "INSERT INTO groups (gid, json, name, short) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (gid) DO UPDATE SET json = $2, name = $3, short = $4",
+            group.gid,
+            json,
+            group.name,
+            group.short,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for file in dump.files.values() {
+        let json = serde_json::to_string(file)?;
+
+        sqlx::query!(
+            "INSERT INTO files (fid, aid, eid, gid, size, ed2k, json)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (fid) DO UPDATE SET
+                aid = $2, eid = $3, gid = $4, size = $5, ed2k = $6, json = $7",
+            file.fid,
+            file.aid,
+            file.eid,
+            file.gid,
+            file.size,
+            file.ed2k,
+            json,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // newer progress from this machine wins
+    for progress in &dump.watch_progress {
+        let last_updated = progress.last_updated.timestamp();
+
+        sqlx::query!(
+            "INSERT INTO watch_progress (aid, last_eid, episode_progress, anime_progress, last_updated)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (aid) DO UPDATE SET
+                last_eid = $2, episode_progress = $3, anime_progress = $4, last_updated = $5
+             WHERE watch_progress.last_updated < $5",
+            progress.aid,
+            progress.last_eid,
+            progress.episode_progress,
+            progress.anime_progress,
+            last_updated,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for links in &dump.platform_links {
+        let id = |id: Option<NonZeroU64>| id.map_or(0, |id| id.get() as i64);
+        let (animebytes_id, anidb_id, ann_id, anilist_id, mal_id) = (
+            id(links.animebytes_id),
+            id(links.anidb_id),
+            id(links.ann_id),
+            id(links.anilist_id),
+            id(links.mal_id),
+        );
+
+        let existing = sqlx::query_scalar!(
+            "SELECT id FROM platform_links WHERE anidb_id = $1 AND anidb_id != 0",
+            anidb_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        // ids known here are kept, the dump only fills in the gaps
+        if let Some(existing) = existing {
+            sqlx::query!(
+                "UPDATE platform_links SET
+                    animebytes_id = coalesce(nullif(animebytes_id, 0), $1),
+                    ann_id = coalesce(nullif(ann_id, 0), $2),
+                    anilist_id = coalesce(nullif(anilist_id, 0), $3),
+                    mal_id = coalesce(nullif(mal_id, 0), $4)
+                 WHERE id = $5",
+                animebytes_id,
+                ann_id,
+                anilist_id,
+                mal_id,
+                existing,
+            )
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query!(
+                "INSERT OR IGNORE INTO platform_links (animebytes_id, anidb_id, ann_id, anilist_id, mal_id)
+                 VALUES ($1, $2, $3, $4, $5)",
+                animebytes_id,
+                anidb_id,
+                ann_id,
+                anilist_id,
+                mal_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    for anime in &dump.anime {
+        titles::add_cached(anime).await?;
+    }
+
+    Ok(())
+}
+
+/// Without `verified` there's no mtime, so the next scan hashes the file instead of taking
+/// the dump's word for it
+async fn index_file(path: &Path, file: &crate::anidb::records::File, verified: bool) -> Result<()> {
+    let meta = path.metadata()?;
+    let utf_path = path.to_string_lossy();
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    let size = meta.len() as i64;
+    let inode = meta.ino() as i64;
+    let mtime = verified.then(|| meta.mtime());
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query!(
+        "INSERT INTO indexed_files (path, filename, filesize, ed2k, fid, inode, mtime, first_seen, last_updated)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT (path) DO UPDATE SET
+            filesize = excluded.filesize, ed2k = excluded.ed2k, fid = excluded.fid,
+            inode = excluded.inode, mtime = excluded.mtime, missing_since = NULL,
+            last_updated = excluded.last_updated",
+        utf_path,
+        filename,
+        size,
+        file.ed2k,
+        file.fid,
+        inode,
+        mtime,
+        now,
+        now
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs;
+
+    use super::*;
+    use crate::{
+        anidb::{
+            mock::fixtures,
+            records::{Anime, File, Record},
+        },
+        indexer::{ed2k, reconcile},
+    };
+
+    #[tokio::test]
+    async fn imports_dump() {
+        let dir = std::env::temp_dir().join(format!("tetsu-import-{}", std::process::id()));
+        let root = dir.join("library");
+        fs::create_dir_all(root.join("Crest of the Stars"))
+            .await
+            .unwrap();
+
+        let contents = b"a video from another machine";
+        let at_path = root.join("Seikai no Monshou/01.mkv");
+        let moved = root.join("Crest of the Stars/02.mkv");
+        let renamed = root.join("Crest of the Stars/episode 3.mkv");
+        fs::create_dir_all(at_path.parent().unwrap()).await.unwrap();
+        for path in [&at_path, &moved, &renamed] {
+            fs::write(path, contents).await.unwrap();
+        }
+
+        let size = contents.len() as i64;
+        let ed2k = ed2k::Hasher::Read
+            .hash_file(&at_path, &ProgressBar::hidden())
+            .unwrap();
+        let file = |fid| File::parse(&fixtures::file(fid, size, &ed2k)).unwrap();
+
+        let dump = DataDump {
+            files: [
+                (PathBuf::from("Seikai no Monshou/01.mkv"), file(401)),
+                (PathBuf::from("Seikai no Monshou/02.mkv"), file(402)),
+                (PathBuf::from("Seikai no Monshou/03.mkv"), file(403)),
+            ]
+            .into(),
+            anime: vec![Anime::parse(fixtures::ANIME).unwrap()],
+            ..Default::default()
+        };
+        let dump_path = dir.join("dump.json");
+        fs::write(&dump_path, serde_json::to_string(&dump).unwrap())
+            .await
+            .unwrap();
+
+        let fid = |path: &Path| {
+            let utf_path = path.to_string_lossy().to_string();
+            async move {
+                sqlx::query_scalar!("SELECT fid FROM indexed_files WHERE path = ?", utf_path)
+                    .fetch_optional(crate::DB.get().await)
+                    .await
+                    .unwrap()
+                    .flatten()
+            }
+        };
+
+        let report = import_dump(&dump_path, &root, false).await.unwrap();
+        assert_eq!((report.anime, report.matched, report.unverified), (1, 2, 1));
+        assert_eq!(report.unmatched, [PathBuf::from("Seikai no Monshou/03.mkv")]);
+        assert_eq!(fid(&at_path).await, Some(401));
+        assert_eq!(fid(&moved).await, Some(402));
+        assert_eq!(fid(&renamed).await, None);
+
+        // only the file at the dump's path is trusted without hashing it
+        let meta = |path: &Path| std::fs::metadata(path).unwrap();
+        let indexed = |path: &Path| {
+            let path = path.to_owned();
+            async move { reconcile::Indexed::get(&path).await.unwrap().unwrap() }
+        };
+        assert!(indexed(&at_path).await.unchanged(&meta(&at_path)));
+        assert!(!indexed(&moved).await.unchanged(&meta(&moved)));
+
+        // every file has the same contents, so hashing matches them all
+        let report = import_dump(&dump_path, &root, true).await.unwrap();
+        assert_eq!(report.matched, 3);
+        assert!(fid(&renamed).await.is_some());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
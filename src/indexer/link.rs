@@ -0,0 +1,163 @@
+//! Manually telling tetsu what a file is, for files AniDB doesn't know by hash. Linked
+//! files, and ones whose episode was guessed from their name, are listed next to
+//! AniDB-identified ones, dressed up as [`File`] records.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::anidb::{
+    records::{Episode, File, Group},
+    Anidb,
+};
+
+pub async fn link(
+    anidb: &mut Anidb,
+    path: &Path,
+    aid: u32,
+    epno: &str,
+    gid: Option<u32>,
+) -> Result<Episode> {
+    let utf_path = path.to_string_lossy();
+
+    let Some(indexed) = sqlx::query!("SELECT fid FROM indexed_files WHERE path = ?", utf_path)
+        .fetch_optional(crate::DB.get().await)
+        .await?
+    else {
+        bail!("{} hasn't been indexed", path.display());
+    };
+
+    if let Some(fid) = indexed.fid {
+        bail!("{} is already known to AniDB as file {fid}", path.display());
+    }
+
+    anidb
+        .anime_by_aid(aid)
+        .await
+        .context("Failed to get anime data from AniDB")?
+        .with_context(|| format!("No anime with aid {aid}"))?;
+
+    let episode = anidb
+        .episode_by_epno(aid, epno)
+        .await
+        .context("Failed to get episode data from AniDB")?
+        .with_context(|| format!("No episode {epno} for anime {aid}"))?;
+
+    if let Some(gid) = gid {
+        anidb
+            .group_by_gid(gid)
+            .await
+            .context("Failed to get group data from AniDB")?
+            .with_context(|| format!("No group with gid {gid}"))?;
+    }
+
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query!(
+        "UPDATE indexed_files
+         SET linked_aid = ?, linked_eid = ?, linked_gid = ?, last_updated = ?
+         WHERE path = ?",
+        aid,
+        episode.eid,
+        gid,
+        now,
+        utf_path,
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(episode)
+}
+
+pub struct LinkedFile {
+    pub path: String,
+    pub file: File,
+    pub group: Group,
+}
+
+/// Files linked to one of the anime's episodes, or guessed to be one of them if they
+/// haven't been linked. Without a group they get a placeholder so they can be listed
+/// like any other file.
+pub async fn linked_files(aid: u32) -> Result<Vec<LinkedFile>> {
+    let db = crate::DB.get().await;
+
+    let rows = sqlx::query!(
+        r#"SELECT if.path, if.filesize, if.ed2k, if.eid AS "eid!: u32", if.linked_gid AS "gid: u32", g.json AS "gjson?"
+         FROM (
+            SELECT *,
+                coalesce(linked_aid, guessed_aid) AS aid,
+                iif(linked_aid IS NULL, guessed_eid, linked_eid) AS eid
+            FROM indexed_files
+         ) if
+         LEFT OUTER JOIN groups g
+            ON if.linked_gid = g.gid
+         WHERE if.fid IS NULL AND if.aid = ? AND if.eid IS NOT NULL
+            AND if.missing_since IS NULL"#,
+        aid
+    )
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            let group = match row.gjson {
+                Some(json) => serde_json::from_str(&json).context("Invalid record in database")?,
+                None => Group {
+                    name: "Unknown".to_string(),
+                    ..Default::default()
+                },
+            };
+
+            Ok(LinkedFile {
+                path: row.path,
+                file: File {
+                    aid,
+                    eid: row.eid,
+                    gid: row.gid.unwrap_or_default(),
+                    size: row.filesize,
+                    ed2k: row.ed2k,
+                    ..Default::default()
+                },
+                group,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::mock::{fixtures, MockServer};
+
+    #[tokio::test]
+    async fn link_unknown_file() {
+        let server = MockServer::logged_in().await;
+        server.add_anime(fixtures::ANIME).unwrap();
+        server.add_episode(fixtures::EPISODE).unwrap();
+        server.add_group(fixtures::GROUP).unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        let path = Path::new("/media/anime/Seikai no Monshou 01 (raw).mkv");
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, first_seen, last_updated)
+             VALUES ('/media/anime/Seikai no Monshou 01 (raw).mkv', 'Seikai no Monshou 01 (raw).mkv', 1234, 'abcd', 0, 0)"
+        )
+        .execute(crate::DB.get().await)
+        .await
+        .unwrap();
+
+        assert!(link(&mut anidb, path, 1, "99", None).await.is_err());
+
+        let episode = link(&mut anidb, path, 1, "01", Some(1)).await.unwrap();
+        assert_eq!(episode.eid, 1);
+
+        let linked = linked_files(1).await.unwrap();
+        let linked = linked
+            .iter()
+            .find(|linked| linked.path == path.to_string_lossy())
+            .unwrap();
+        assert_eq!((linked.file.eid, linked.file.size), (1, 1234));
+        assert_eq!(linked.group.short, "TF");
+    }
+}
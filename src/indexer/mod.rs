@@ -1,33 +1,105 @@
 use std::{
+    collections::HashMap,
+    fs::Metadata,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    process::Termination,
+    sync::Arc,
     time::Duration,
 };
 
 use anyhow::{Context, Result};
+use futures::{stream::FuturesUnordered, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use tokio::{fs, sync::mpsc};
+use tokio::{
+    fs,
+    sync::{mpsc, Semaphore},
+};
 
 use crate::ANIDB;
 
+pub mod dry_run;
 pub mod dump;
 pub mod ed2k;
+pub mod failures;
+pub mod filter;
+pub mod import;
+pub mod link;
+pub mod mylist;
+pub mod nfo;
+pub mod organize;
 pub mod playlist;
+pub mod reconcile;
+pub mod release_name;
+pub mod verify;
+pub mod watch;
+
+#[derive(Debug, Clone, Default)]
+pub struct IndexOptions {
+    /// Add identified files to the user's AniDB mylist
+    pub add_to_mylist: bool,
+    /// Re-hash files even if they look unchanged, reporting the ones that no longer match
+    pub verify: bool,
+    /// Overrides the hasher from the config
+    pub hasher: Option<ed2k::Hasher>,
+    /// Compute CRC32, MD5 and SHA1 along with ed2k, on top of the config
+    pub extra_hashes: bool,
+    /// Replaces the included globs from the config
+    pub include: Option<Vec<String>>,
+    /// Excluded on top of the globs from the config
+    pub exclude: Vec<String>,
+    /// Replaces the extension allowlist from the config
+    pub extensions: Option<Vec<String>>,
+}
 
 #[derive(Debug)]
 struct AnidbRequestHandoff {
-    hash: String,
+    hashes: ed2k::Hashes,
     file_path: PathBuf,
     pb: ProgressBar,
 }
 
-pub async fn index(path: &Path) -> Result<()> {
+pub async fn index(path: &Path, options: &IndexOptions) -> Result<()> {
+    let (mpb, overall) = progress_bars();
+    overall.set_message("Building file list...");
+
+    let config = crate::CONFIG.read().await.index.clone();
+    let filter = filter::FileFilter::new(path, &config, options)?;
+    let files = walk(path, &filter).await;
+    overall.inc_length(files.len() as u64);
+
+    index_files(&mpb, &overall, files, options).await?;
+
+    let missing = reconcile::check_missing(path).await?;
+    if missing > 0 {
+        log::warn!("{missing} indexed files are missing, run `tetsu gc` to forget them");
+    }
+
+    Ok(())
+}
+
+/// Index the files earlier runs failed on, those at or below `path` if given
+pub async fn retry_failed(path: Option<&Path>, options: &IndexOptions) -> Result<()> {
+    let mut files = vec![];
+
+    for file in failures::failed(path).await? {
+        if file.is_file() {
+            files.push(file);
+        } else {
+            log::info!("{} is gone, not retrying it", file.display());
+            failures::clear(&file).await?;
+        }
+    }
+
+    let (mpb, overall) = progress_bars();
+    overall.inc_length(files.len() as u64);
+
+    index_files(&mpb, &overall, files, options).await
+}
+
+fn progress_bars() -> (MultiProgress, ProgressBar) {
     let mpb = MultiProgress::new();
     crate::PROGRESS_BAR.write().unwrap().replace(mpb.clone());
 
-    let (tx, rx) = mpsc::channel(10);
-    let anidb_task_handle = tokio::spawn(get_anidb_data_task(rx));
-
     let overall = mpb.add(ProgressBar::new(0));
     // overall.enable_steady_tick(Duration::from_millis(125));
     overall.set_style(
@@ -36,66 +108,182 @@ pub async fn index(path: &Path) -> Result<()> {
             .template("[{elapsed_precise}] [{bar:32.cyan/blue}] {pos:.green}/{len:.blue} ({eta:.yellow}) {wide_msg}")
             .unwrap(),
     );
-    overall.set_message("Building file list...");
+
+    (mpb, overall)
+}
+
+/// Files below `path` that pass the filter, or `path` itself if it's a file
+async fn walk(path: &Path, filter: &filter::FileFilter) -> Vec<PathBuf> {
+    walk_filtered(path, filter).await.0
+}
+
+/// Like [`walk`], but also returns the files and directories the filter left out
+async fn walk_filtered(path: &Path, filter: &filter::FileFilter) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    if path.is_file() {
+        return (vec![path.to_owned()], vec![]);
+    }
 
     let mut dirs = vec![path.to_owned()];
     let mut files = vec![];
+    let mut filtered = vec![];
 
     while let Some(dir) = dirs.pop() {
-        let mut rd = fs::read_dir(dir).await.unwrap();
-        while let Some(entry) = rd.next_entry().await.unwrap() {
+        // one unreadable directory shouldn't keep the rest of the library from being indexed
+        let mut rd = match fs::read_dir(&dir).await {
+            Ok(rd) => rd,
+            Err(e) => {
+                log::warn!("Skipping {}: {e}", dir.display());
+                continue;
+            }
+        };
+
+        loop {
+            let entry = match rd.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("Failed to read {}: {e}", dir.display());
+                    break;
+                }
+            };
+
             let path = entry.path();
-            if path.is_dir() {
-                dirs.push(path);
+            let allowed = if path.is_dir() {
+                filter.allows_dir(&path)
             } else {
-                files.push(path);
-                overall.inc_length(1);
+                filter.allows_file(&path)
+            };
+
+            match (allowed, path.is_dir()) {
+                (true, true) => dirs.push(path),
+                (true, false) => files.push(path),
+                (false, _) => filtered.push(path),
             }
         }
     }
 
+    (files, filtered)
+}
+
+/// A file that needs hashing, along with what we knew about it before
+struct HashJob {
+    file_path: PathBuf,
+    meta: Metadata,
+    indexed: Option<reconcile::Indexed>,
+}
+
+/// Files go through three stages: checking whether they need hashing at all, hashing a
+/// few at a time with a limit on concurrent reads per device, and deciding what to do
+/// with the hash. Whatever AniDB has to identify is handed off to [`get_anidb_data_task`].
+async fn index_files(
+    mpb: &MultiProgress,
+    overall: &ProgressBar,
+    files: Vec<PathBuf>,
+    options: &IndexOptions,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel(10);
+    let anidb_task_handle = tokio::spawn(get_anidb_data_task(rx, options.clone()));
+
+    let config = crate::CONFIG.read().await.index.clone();
+    let hasher = options.hasher.unwrap_or(config.hasher);
+    let extra_hashes = options.extra_hashes || config.extra_hashes;
+    let mut devices = HashMap::<u64, Arc<Semaphore>>::new();
+
     overall.reset_eta();
     overall.set_message("Indexing files...");
 
-    for file_path in files {
-        let utf_path = file_path.to_string_lossy();
-        let in_db = sqlx::query!("SELECT path FROM indexed_files WHERE path = ?", utf_path)
-            .fetch_optional(crate::DB.get().await)
-            .await?
-            .is_some();
-
-        if in_db {
-            overall.inc(1);
-            continue;
+    let mut corrupted = 0;
+    let mut failed = 0;
+    let mut files = files.into_iter();
+    let mut hashing = FuturesUnordered::new();
+
+    loop {
+        // keep the hashing stage busy
+        while hashing.len() < config.hash_jobs.max(1) {
+            let Some(file_path) = files.next() else {
+                break;
+            };
+
+            let job = match prepare(&file_path, options).await {
+                Ok(Some(job)) => job,
+                Ok(None) => {
+                    overall.inc(1);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Failed to index {}: {e:#}", file_path.display());
+                    failures::record(&file_path, &e).await?;
+                    failed += 1;
+                    overall.inc(1);
+                    continue;
+                }
+            };
+
+            let device = devices
+                .entry(job.meta.dev())
+                .or_insert_with(|| Arc::new(Semaphore::new(config.io_per_device.max(1))))
+                .clone();
+
+            hashing.push(hash_job(job, hasher, extra_hashes, device, mpb.clone(), overall.clone()));
         }
 
-        let pb = mpb.insert_before(&overall, ProgressBar::new(0));
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .progress_chars("== ")
-                .template("[{elapsed_precise}] [{bar:32.cyan/blue}] {spinner:.green} {wide_msg}")
-                .unwrap(),
-        );
+        let Some(hashed) = hashing.next().await else {
+            break;
+        };
 
-        pb.set_message(
-            file_path
-                .file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string(),
-        );
+        let (HashJob { file_path, meta, indexed }, pb, hashes) = hashed;
 
-        pb.enable_steady_tick(Duration::from_millis(125));
+        let hashes = match hashes {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                failure_progress_bar(&pb, &file_path);
+                failures::record(&file_path, &e.context("Failed to hash file")).await?;
+                failed += 1;
+                overall.inc(1);
+                continue;
+            }
+        };
+
+        match indexed {
+            Some(indexed) => {
+                if hashes.ed2k == indexed.expected_ed2k().await? {
+                    reconcile::touch(&file_path, &meta).await?;
+                    if extra_hashes {
+                        store_extra_hashes(&file_path, &hashes).await?;
+                    }
+                    failures::clear(&file_path).await?;
+                    pb.finish_and_clear();
+                    overall.inc(1);
+                    continue;
+                }
 
-        let hash = tokio::task::block_in_place(|| ed2k::hash_file(&file_path, &pb).unwrap());
+                // same size and mtime but different contents, that's not an edit
+                if options.verify && indexed.fid.is_some() && indexed.unchanged(&meta) {
+                    pb.set_style(
+                        ProgressStyle::default_bar()
+                            .template("[{elapsed_precise}] {spinner:.green} {wide_msg:.red}")
+                            .unwrap(),
+                    );
+                    pb.finish_with_message(format!("Corrupted: {}", file_path.display()));
+                    log::warn!("{} no longer matches its AniDB hash", file_path.display());
+
+                    corrupted += 1;
+                    overall.inc(1);
+                    continue;
+                }
 
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {spinner:.green} {wide_msg}")
-                .unwrap(),
-        );
+                // replaced in place
+                reconcile::forget(&file_path).await?;
+            }
+            None if reconcile::relocate(&file_path, &meta, Some(&hashes.ed2k)).await? => {
+                pb.finish_with_message(format!("Moved: {}", file_path.display()));
+                overall.inc(1);
+                continue;
+            }
+            None => (),
+        }
 
-        tx.send(AnidbRequestHandoff { hash, file_path, pb })
+        tx.send(AnidbRequestHandoff { hashes, file_path, pb })
             .await
             .unwrap();
 
@@ -105,46 +293,203 @@ pub async fn index(path: &Path) -> Result<()> {
     overall.set_message("Waiting for AniDB data...");
 
     drop(tx);
-    anidb_task_handle.await.unwrap();
+    failed += anidb_task_handle.await.unwrap();
 
-    overall.finish_with_message("Done!");
+    let mut problems = vec![];
+    if corrupted > 0 {
+        problems.push(format!("{corrupted} corrupted"));
+    }
+    if failed > 0 {
+        problems.push(format!("{failed} failed"));
+        log::warn!("{failed} files failed, run `tetsu index --retry-failed` to try them again");
+    }
+
+    if problems.is_empty() {
+        overall.finish_with_message("Done!");
+    } else {
+        overall.finish_with_message(format!("Done! {} files", problems.join(", ")));
+    }
 
     crate::PROGRESS_BAR.write().unwrap().take();
 
     Ok(())
 }
 
-async fn get_anidb_data_task(mut rx: mpsc::Receiver<AnidbRequestHandoff>) {
+async fn hash_job(
+    job: HashJob,
+    hasher: ed2k::Hasher,
+    extra_hashes: bool,
+    device: Arc<Semaphore>,
+    mpb: MultiProgress,
+    overall: ProgressBar,
+) -> (HashJob, ProgressBar, Result<ed2k::Hashes>) {
+    let permit = device
+        .acquire_owned()
+        .await
+        .expect("device semaphores are never closed");
+    let pb = file_progress_bar(&mpb, &overall, &job.file_path);
+
+    let hashes = {
+        let (file_path, pb) = (job.file_path.clone(), pb.clone());
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            hasher.hash_file_with(&file_path, &pb, extra_hashes)
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()))
+    };
+
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {spinner:.green} {wide_msg}")
+            .unwrap(),
+    );
+
+    (job, pb, hashes)
+}
+
+async fn store_extra_hashes(file_path: &Path, hashes: &ed2k::Hashes) -> Result<()> {
+    let utf_path = file_path.to_string_lossy();
+
+    sqlx::query!(
+        "UPDATE indexed_files SET crc32 = ?, md5 = ?, sha1 = ? WHERE path = ?",
+        hashes.crc32,
+        hashes.md5,
+        hashes.sha1,
+        utf_path
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(())
+}
+
+/// Decide whether a file needs hashing, taking care of the ones that don't
+async fn prepare(file_path: &Path, options: &IndexOptions) -> Result<Option<HashJob>> {
+    let meta = file_path
+        .metadata()
+        .context("Failed to read file metadata")?;
+    let indexed = reconcile::Indexed::get(file_path).await?;
+
+    match indexed {
+        Some(ref indexed) if !options.verify && indexed.unchanged(&meta) => {
+            if indexed.mtime.is_none() {
+                reconcile::touch(file_path, &meta).await?;
+            }
+
+            Ok(None)
+        }
+        None if reconcile::relocate(file_path, &meta, None).await? => Ok(None),
+        _ => Ok(Some(HashJob {
+            file_path: file_path.to_owned(),
+            meta,
+            indexed,
+        })),
+    }
+}
+
+fn file_progress_bar(mpb: &MultiProgress, overall: &ProgressBar, file_path: &Path) -> ProgressBar {
+    let pb = mpb.insert_before(overall, ProgressBar::new(0));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .progress_chars("== ")
+            .template("[{elapsed_precise}] [{bar:32.cyan/blue}] {spinner:.green} {wide_msg}")
+            .unwrap(),
+    );
+
+    pb.set_message(
+        file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    );
+
+    pb.enable_steady_tick(Duration::from_millis(125));
+
+    pb
+}
+
+fn failure_progress_bar(pb: &ProgressBar, file_path: &Path) {
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {spinner:.green} {wide_msg:.red}")
+            .unwrap(),
+    );
+    pb.finish_with_message(format!("Failed: {}", file_path.display()));
+}
+
+/// AniDB errors in a row, less one per success, before lookups are given up on
+const MAX_ANIDB_ERRORS: u32 = 5;
+
+/// Identifies files with AniDB until the channel closes, returning how many failed. After a
+/// few errors in a row AniDB is left alone for the rest of the run, the remaining files are
+/// recorded as failed so `--retry-failed` can pick them up.
+async fn get_anidb_data_task(
+    mut rx: mpsc::Receiver<AnidbRequestHandoff>,
+    options: IndexOptions,
+) -> usize {
     let mut errors = 0u32;
+    let mut failed = 0;
 
     while let Some(handoff) = rx.recv().await {
-        match get_anidb_data(handoff).await {
-            Ok(()) => errors = errors.saturating_sub(1),
-            res @ Err(_) => {
-                if errors >= 5 {
-                    log::error!("Too many errors, aborting.");
-                    break;
+        let file_path = handoff.file_path.clone();
+        let pb = handoff.pb.clone();
+
+        let res = if errors >= MAX_ANIDB_ERRORS {
+            log::warn!("Not looking up {}, AniDB lookups are disabled", file_path.display());
+
+            failure_progress_bar(&pb, &file_path);
+            failed += 1;
+            let e = anyhow::anyhow!("AniDB lookups disabled after repeated errors");
+            failures::record(&file_path, &e).await
+        } else {
+            match get_anidb_data(handoff, &options).await {
+                Ok(()) => {
+                    errors = errors.saturating_sub(1);
+                    failures::clear(&file_path).await
+                }
+                Err(e) => {
+                    errors += 1;
+                    log::error!("Failed to index {}: {e:#}", file_path.display());
+
+                    if errors >= MAX_ANIDB_ERRORS {
+                        log::error!(
+                            "Too many AniDB errors, the remaining files are recorded for \
+                             --retry-failed without looking them up"
+                        );
+                    }
+
+                    failure_progress_bar(&pb, &file_path);
+                    failed += 1;
+                    failures::record(&file_path, &e).await
                 }
-
-                // errors += 1;
-                // log::error!("Error: {e}");
-
-                Termination::report(res);
-                std::process::exit(1);
             }
+        };
+
+        if let Err(e) = res {
+            log::error!("Failed to update index failures: {e:#}");
         }
     }
+
+    failed
 }
 
 async fn get_anidb_data(
-    AnidbRequestHandoff { hash, file_path, pb }: AnidbRequestHandoff,
+    AnidbRequestHandoff { hashes, file_path, pb }: AnidbRequestHandoff,
+    options: &IndexOptions,
 ) -> Result<()> {
-    let size = file_path.metadata().unwrap().len() as i64;
+    let meta = file_path.metadata()?;
+    let size = meta.len() as i64;
+    let inode = meta.ino() as i64;
+    let mtime = meta.mtime();
+    let utf_name = file_path.file_name().unwrap().to_string_lossy();
+    let mut guess = None;
 
     let mut anidb = ANIDB.write().await;
 
     let anidb_file = anidb
-        .file_by_ed2k(size, &hash)
+        .file_by_ed2k(size, &hashes.ed2k)
         .await
         .context("Failed to get file data from AniDB")?;
 
@@ -183,6 +528,12 @@ async fn get_anidb_data(
                 }
             }
         }
+
+        if options.add_to_mylist {
+            mylist::add_file(&mut anidb, anidb_file.fid)
+                .await
+                .context("Failed to add file to mylist")?;
+        }
     } else {
         pb.set_style(
             ProgressStyle::default_bar()
@@ -190,23 +541,43 @@ async fn get_anidb_data(
                 .unwrap(),
         );
 
-        pb.set_message(format!("Not found: {}", file_path.display()));
+        guess = release_name::guess(&mut anidb, &utf_name).await?;
+
+        match guess {
+            Some(ref guess) => pb.set_message(format!(
+                "Guessed ({:.0}%): {}",
+                guess.confidence * 100.,
+                file_path.display()
+            )),
+            None => pb.set_message(format!("Not found: {}", file_path.display())),
+        }
     }
 
     drop(anidb);
 
     let utf_path = file_path.to_string_lossy();
-    let utf_name = file_path.file_name().unwrap().to_string_lossy();
     let now = chrono::Utc::now().timestamp();
     let fid = anidb_file.map(|f| f.fid);
+    let guessed_aid = guess.as_ref().map(|g| g.aid);
+    let guessed_eid = guess.as_ref().and_then(|g| g.eid);
+    let confidence = guess.as_ref().map(|g| g.confidence);
 
     sqlx::query!(
-            "INSERT INTO indexed_files (path, filename, filesize, ed2k, fid, first_seen, last_updated) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, crc32, md5, sha1, fid, guessed_aid, guessed_eid, confidence, inode, mtime, first_seen, last_updated)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
             utf_path,
             utf_name,
             size,
-            hash,
+            hashes.ed2k,
+            hashes.crc32,
+            hashes.md5,
+            hashes.sha1,
             fid,
+            guessed_aid,
+            guessed_eid,
+            confidence,
+            inode,
+            mtime,
             now,
             now,
         )
@@ -217,3 +588,141 @@ async fn get_anidb_data(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::{
+        mock::{fixtures, MockServer},
+        records::MylistState,
+        Anidb,
+    };
+
+    #[tokio::test]
+    async fn walk_skips_unreadable_dirs() {
+        let missing = std::env::temp_dir().join(format!("tetsu-missing-{}", std::process::id()));
+        let config = crate::config::IndexConfig::default();
+        let filter = filter::FileFilter::new(&missing, &config, &IndexOptions::default()).unwrap();
+
+        assert_eq!(walk_filtered(&missing, &filter).await, (vec![], vec![]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn index_identifies_files() {
+        let server = MockServer::logged_in().await;
+
+        let dir = std::env::temp_dir().join(format!("tetsu-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let file_path = dir.join("[TF] Seikai no Monshou - 01.mkv");
+        let contents = b"definitely a video file";
+        fs::write(&file_path, contents).await.unwrap();
+
+        let hash = ed2k::hash_file(&file_path, &ProgressBar::hidden()).unwrap();
+
+        server
+            .add_file(&fixtures::file(200, contents.len() as i64, &hash))
+            .unwrap();
+        server.add_anime(fixtures::ANIME).unwrap();
+        server.add_episode(fixtures::EPISODE).unwrap();
+        server.add_group(fixtures::GROUP).unwrap();
+
+        *ANIDB.write().await = Anidb::with_config(server.config());
+
+        index(
+            &dir,
+            &IndexOptions {
+                add_to_mylist: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let utf_path = file_path.to_string_lossy();
+        let fid = sqlx::query_scalar!("SELECT fid FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_one(crate::DB.get().await)
+            .await
+            .unwrap();
+
+        assert_eq!(fid, Some(200));
+
+        let episode = ANIDB
+            .write()
+            .await
+            .episode_by_eid(1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(episode.romaji, "Shinryaku");
+
+        let mylist = server.mylist();
+        assert_eq!(mylist.len(), 1);
+        assert_eq!(mylist[0].fid, 200);
+        assert_eq!(mylist[0].state, MylistState::Hdd);
+
+        // AniDB doesn't know this one, but everything its name mentions is cached by now
+        let unknown_path = dir.join("[TF] Crest of the Stars - 01 [1080p][0BADF00D].mkv");
+        fs::write(&unknown_path, b"some other video file")
+            .await
+            .unwrap();
+
+        index(&dir, &IndexOptions::default()).await.unwrap();
+
+        let utf_path = unknown_path.to_string_lossy();
+        let row = sqlx::query!(
+            "SELECT fid, guessed_aid, guessed_eid, confidence FROM indexed_files WHERE path = ?",
+            utf_path
+        )
+        .fetch_one(crate::DB.get().await)
+        .await
+        .unwrap();
+
+        assert_eq!(row.fid, None);
+        assert_eq!(row.guessed_aid, Some(1));
+        assert_eq!(row.guessed_eid, Some(1));
+        assert!(row.confidence.unwrap() > 0.99);
+
+        // and it's listed with the anime's files
+        let listed = link::linked_files(1).await.unwrap();
+        assert!(listed.iter().any(|linked| linked.path == utf_path));
+
+        // bit rot keeps size and mtime, so only --verify notices
+        let mtime = file_path.metadata().unwrap().modified().unwrap();
+        fs::write(&file_path, b"definitely a video fil\0")
+            .await
+            .unwrap();
+        let set_mtime = |mtime| {
+            std::fs::File::options()
+                .write(true)
+                .open(&file_path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap()
+        };
+        set_mtime(mtime);
+
+        let verify = IndexOptions { verify: true, ..Default::default() };
+        index(&dir, &verify).await.unwrap();
+
+        let utf_path = file_path.to_string_lossy();
+        let row = sqlx::query!("SELECT fid, ed2k FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_one(crate::DB.get().await)
+            .await
+            .unwrap();
+        assert_eq!((row.fid, row.ed2k), (Some(200), hash.clone()));
+
+        // an edit changes the mtime, and the file gets identified again
+        set_mtime(mtime + Duration::from_secs(10));
+        index(&dir, &IndexOptions::default()).await.unwrap();
+
+        let row = sqlx::query!("SELECT fid, ed2k FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_one(crate::DB.get().await)
+            .await
+            .unwrap();
+        assert_eq!(row.fid, None);
+        assert_ne!(row.ed2k, hash);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+
+use crate::anidb::{
+    records::{MylistEntry, MylistState},
+    Anidb, MylistAdd, MylistUpdate,
+};
+
+fn on_hdd() -> MylistUpdate {
+    MylistUpdate {
+        state: Some(MylistState::Hdd),
+        ..Default::default()
+    }
+}
+
+/// Make sure the file is in the user's mylist and marked as being on disk
+pub async fn add_file(anidb: &mut Anidb, fid: u32) -> Result<MylistAdd> {
+    let res = anidb.mylist_add(fid, &on_hdd()).await?;
+
+    if let MylistAdd::AlreadyInMylist(ref entry) = res {
+        if entry.state != MylistState::Hdd {
+            anidb.mylist_edit(entry.lid, &on_hdd()).await?;
+        }
+    }
+
+    Ok(res)
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Identified files that are indexed and not missing
+    pub on_disk: usize,
+    pub added: usize,
+    /// Marked as deleted because the file isn't indexed anymore
+    pub removed: usize,
+    /// Removed from the mylist on AniDB, and now from the cache too
+    pub forgotten: usize,
+    /// Files AniDB doesn't know
+    pub unknown: Vec<u32>,
+}
+
+/// Bring the mylist in line with `indexed_files`. Every entry involved is read from AniDB
+/// first, so changes made on the website are seen: identified files on disk end up in the
+/// mylist marked as such, and entries on disk for files that aren't indexed anymore are
+/// marked as deleted.
+pub async fn sync(anidb: &mut Anidb) -> Result<Report> {
+    let db = crate::DB.get().await;
+
+    let local = sqlx::query_scalar!(
+        "SELECT DISTINCT fid FROM indexed_files WHERE fid IS NOT NULL AND missing_since IS NULL"
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .flatten()
+    .filter_map(|fid| u32::try_from(fid).ok())
+    .collect::<HashSet<_>>();
+
+    let mut report = Report {
+        on_disk: local.len(),
+        ..Default::default()
+    };
+
+    for &fid in &local {
+        let remote = anidb
+            .fetch_mylist_by_fid(fid)
+            .await
+            .with_context(|| format!("Failed to read the mylist entry for file {fid}"))?;
+
+        match remote {
+            Some(entry) if entry.state == MylistState::Hdd => (),
+            Some(entry) => {
+                anidb
+                    .mylist_edit(entry.lid, &on_hdd())
+                    .await
+                    .with_context(|| format!("Failed to update mylist entry {}", entry.lid))?;
+            }
+            None => {
+                let res = add_file(anidb, fid)
+                    .await
+                    .with_context(|| format!("Failed to add file {fid} to mylist"))?;
+
+                match res {
+                    MylistAdd::Added(_) => report.added += 1,
+                    MylistAdd::AlreadyInMylist(_) => (),
+                    MylistAdd::NoSuchFile => report.unknown.push(fid),
+                }
+            }
+        }
+    }
+
+    let stale = sqlx::query!("SELECT json FROM mylist")
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|row| serde_json::from_str(&row.json).context("Invalid record in database"))
+        .collect::<Result<Vec<MylistEntry>>>()?
+        .into_iter()
+        .filter(|entry| entry.state == MylistState::Hdd && !local.contains(&entry.fid));
+
+    let deleted = MylistUpdate {
+        state: Some(MylistState::Deleted),
+        ..Default::default()
+    };
+
+    for entry in stale {
+        let remote = anidb
+            .fetch_mylist_by_fid(entry.fid)
+            .await
+            .with_context(|| format!("Failed to read mylist entry {}", entry.lid))?;
+
+        match remote {
+            None => report.forgotten += 1,
+            Some(remote) if remote.state == MylistState::Hdd => {
+                if anidb
+                    .mylist_edit(remote.lid, &deleted)
+                    .await
+                    .with_context(|| format!("Failed to update mylist entry {}", remote.lid))?
+                {
+                    report.removed += 1;
+                }
+            }
+            // already changed on AniDB
+            Some(_) => (),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::mock::{fixtures, MockServer};
+
+    #[tokio::test]
+    async fn sync_reads_remote_state() {
+        let server = MockServer::logged_in().await;
+        server.add_file(&fixtures::file(601, 24, "abcd")).unwrap();
+        server.add_file(&fixtures::file(602, 24, "efgh")).unwrap();
+
+        let mut anidb = Anidb::with_config(server.config());
+
+        // on disk according to the cache, but removed on the website since
+        anidb.mylist_add(601, &on_hdd()).await.unwrap();
+        server.remove_from_mylist(601);
+
+        let path = std::env::temp_dir()
+            .join(format!("tetsu-mylist-{}", std::process::id()))
+            .join("02.mkv");
+        let utf_path = path.to_string_lossy();
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, fid, first_seen, last_updated)
+             VALUES (?, '02.mkv', 24, 'efgh', 602, 0, 0)",
+            utf_path
+        )
+        .execute(crate::DB.get().await)
+        .await
+        .unwrap();
+
+        sync(&mut anidb).await.unwrap();
+
+        let remote = server.mylist();
+        assert!(remote
+            .iter()
+            .any(|e| e.fid == 602 && e.state == MylistState::Hdd));
+        assert!(remote.iter().all(|e| e.fid != 601));
+
+        let cached = sqlx::query_scalar!("SELECT fid FROM mylist WHERE fid = 601")
+            .fetch_optional(crate::DB.get().await)
+            .await
+            .unwrap();
+        assert_eq!(cached, None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Kodi style `.nfo` sidecars, which Jellyfin and Kodi read instead of scraping metadata
+//! themselves. Every anime gets a `tvshow.nfo` in the directory holding its files and
+//! every file an episode `.nfo` next to it. Posters are copied from the image cache when
+//! the anime's picture is in it, nothing is downloaded.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::fs;
+
+use super::dump::{self, DataDump};
+use crate::{
+    anidb::records::{Anime, Episode, File},
+    config::AnidbConfig,
+};
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub shows: usize,
+    pub episodes: usize,
+    pub posters: usize,
+    /// Shows whose picture isn't in the image cache
+    pub missing_posters: usize,
+}
+
+/// Where AniDB pictures are looked for, named by their `picname`
+pub fn image_cache_dir(config: &AnidbConfig) -> Result<PathBuf> {
+    if let Some(ref dir) = config.image_cache {
+        return match dir.strip_prefix("~") {
+            Ok(rest) => Ok(PathBuf::from(std::env::var("HOME")?).join(rest)),
+            Err(_) => Ok(dir.clone()),
+        };
+    }
+
+    let cache = match std::env::var("XDG_CACHE_HOME") {
+        Ok(cache) => PathBuf::from(cache),
+        Err(_) => PathBuf::from(std::env::var("HOME")?).join(".cache"),
+    };
+
+    Ok(cache.join("tetsu/images"))
+}
+
+pub async fn export(dir: &Path, image_cache: &Path) -> Result<Report> {
+    let DataDump { files, anime, episodes, .. } = dump::collect(dir).await?;
+
+    let anime = anime
+        .into_iter()
+        .map(|a| (a.aid, a))
+        .collect::<HashMap<_, _>>();
+    let episodes = episodes
+        .into_iter()
+        .map(|e| (e.eid, e))
+        .collect::<HashMap<_, _>>();
+
+    let mut report = Report::default();
+    let mut shows = BTreeMap::<u32, Vec<&Path>>::new();
+
+    for (path, file) in &files {
+        let (Some(anime), Some(episode)) = (anime.get(&file.aid), episodes.get(&file.eid)) else {
+            continue;
+        };
+
+        let nfo = path.with_extension("nfo");
+        fs::write(&nfo, episode_nfo(anime, episode, file))
+            .await
+            .with_context(|| format!("Failed to write {}", nfo.display()))?;
+
+        report.episodes += 1;
+        shows.entry(file.aid).or_default().push(path);
+    }
+
+    // a directory can only describe one show
+    let mut show_dirs = HashMap::<PathBuf, Vec<u32>>::new();
+    for (aid, paths) in &shows {
+        show_dirs.entry(common_dir(paths)).or_default().push(*aid);
+    }
+
+    for (show_dir, aids) in show_dirs {
+        let [aid] = aids[..] else {
+            log::warn!(
+                "{} holds files of {} anime, not writing a tvshow.nfo there",
+                show_dir.display(),
+                aids.len()
+            );
+            continue;
+        };
+
+        let anime = &anime[&aid];
+        let description =
+            sqlx::query_scalar!("SELECT description FROM anime_descriptions WHERE aid = ?", aid)
+                .fetch_optional(crate::DB.get().await)
+                .await?
+                .flatten();
+
+        let nfo = show_dir.join("tvshow.nfo");
+        fs::write(&nfo, tvshow_nfo(anime, description.as_deref()))
+            .await
+            .with_context(|| format!("Failed to write {}", nfo.display()))?;
+        report.shows += 1;
+
+        let poster = show_dir.join("poster.jpg");
+        if anime.picname.is_empty() || poster.exists() {
+            continue;
+        }
+
+        let picture = image_cache.join(&anime.picname);
+        if !picture.is_file() {
+            log::debug!("{} isn't cached, no poster for anime {aid}", picture.display());
+            report.missing_posters += 1;
+            continue;
+        }
+
+        fs::copy(&picture, &poster)
+            .await
+            .with_context(|| format!("Failed to write {}", poster.display()))?;
+        report.posters += 1;
+    }
+
+    Ok(report)
+}
+
+/// The deepest directory all of `paths` are in
+fn common_dir(paths: &[&Path]) -> PathBuf {
+    let mut common = paths[0].parent().unwrap_or(Path::new("")).to_owned();
+
+    for path in &paths[1..] {
+        while !path.starts_with(&common) {
+            if !common.pop() {
+                break;
+            }
+        }
+    }
+
+    common
+}
+
+/// Kodi season and episode numbers for an AniDB episode. Regular episodes are season 1,
+/// everything else goes into the specials season, with credits, trailers, parodies and
+/// other episodes numbered from 101, 201, 301 and 401 so they don't collide.
+pub fn season_episode(episode: &Episode) -> (u32, u32) {
+    let number = episode
+        .epno
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .parse::<u32>()
+        .unwrap_or(0);
+
+    match episode.etype {
+        1 => (1, number),
+        2 => (0, number),
+        3 => (0, 100 + number),
+        4 => (0, 200 + number),
+        5 => (0, 300 + number),
+        _ => (0, 400 + number),
+    }
+}
+
+fn episode_nfo(anime: &Anime, episode: &Episode, file: &File) -> String {
+    let (season, number) = season_episode(episode);
+    let title = [&episode.eng, &episode.romaji, &episode.kanji]
+        .into_iter()
+        .find(|title| !title.is_empty())
+        .map_or_else(|| format!("Episode {}", episode.epno), String::clone);
+
+    let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+    nfo.push_str("<episodedetails>\n");
+    element(&mut nfo, "title", &title);
+    element(&mut nfo, "originaltitle", &episode.kanji);
+    element(&mut nfo, "showtitle", &anime.romaji_name);
+    element(&mut nfo, "season", &season.to_string());
+    element(&mut nfo, "episode", &number.to_string());
+    if let Some(aired) = date(episode.aired) {
+        element(&mut nfo, "aired", &aired);
+    }
+    if episode.length > 0 {
+        element(&mut nfo, "runtime", &episode.length.to_string());
+    }
+    if episode.votes > 0 {
+        element(&mut nfo, "rating", &format!("{:.2}", episode.rating as f64 / 100.));
+    }
+    let _ = writeln!(nfo, "  <uniqueid type=\"anidb\" default=\"true\">{}</uniqueid>", episode.eid);
+    let _ = writeln!(nfo, "  <uniqueid type=\"anidb_file\">{}</uniqueid>", file.fid);
+    nfo.push_str("</episodedetails>\n");
+
+    nfo
+}
+
+fn tvshow_nfo(anime: &Anime, description: Option<&str>) -> String {
+    let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+    nfo.push_str("<tvshow>\n");
+    element(&mut nfo, "title", &anime.romaji_name);
+    element(&mut nfo, "originaltitle", &anime.kanji_name);
+    element(&mut nfo, "sorttitle", &anime.romaji_name);
+    if let Some(description) = description {
+        element(&mut nfo, "plot", description);
+    }
+    if let Some(premiered) = date(anime.air_date) {
+        element(&mut nfo, "year", &premiered[..4]);
+        element(&mut nfo, "premiered", &premiered);
+    }
+    let _ = writeln!(nfo, "  <uniqueid type=\"anidb\" default=\"true\">{}</uniqueid>", anime.aid);
+    let _ = writeln!(nfo, "  <namedseason number=\"0\">Specials</namedseason>");
+    nfo.push_str("</tvshow>\n");
+
+    nfo
+}
+
+/// AniDB uses the epoch for unknown dates
+fn date(date: DateTime<Utc>) -> Option<String> {
+    (date != DateTime::UNIX_EPOCH).then(|| date.format("%Y-%m-%d").to_string())
+}
+
+fn element(nfo: &mut String, name: &str, value: &str) {
+    if !value.is_empty() {
+        let _ = writeln!(nfo, "  <{name}>{}</{name}>", escape(value));
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::{mock::fixtures, records::Record};
+
+    #[test]
+    fn episode_numbers() {
+        let episode = |epno: &str, etype| Episode {
+            epno: epno.to_string(),
+            etype,
+            ..Default::default()
+        };
+
+        assert_eq!(season_episode(&episode("12", 1)), (1, 12));
+        assert_eq!(season_episode(&episode("S2", 2)), (0, 2));
+        assert_eq!(season_episode(&episode("C1", 3)), (0, 101));
+        assert_eq!(season_episode(&episode("T3", 4)), (0, 203));
+    }
+
+    #[test]
+    fn nfo_contents() {
+        let anime = Anime::parse(fixtures::ANIME).unwrap();
+        let episode = Episode::parse(fixtures::EPISODE).unwrap();
+        let file = File::parse(&fixtures::file(300, 24, "abcd")).unwrap();
+
+        let nfo = episode_nfo(&anime, &episode, &file);
+        assert!(nfo.contains("<title>Invasion</title>"));
+        assert!(nfo.contains("<showtitle>Seikai no Monshou</showtitle>"));
+        assert!(nfo.contains("<season>1</season>\n  <episode>1</episode>"));
+        assert!(nfo.contains("<aired>2000-01-01</aired>"));
+        assert!(nfo.contains("<uniqueid type=\"anidb\" default=\"true\">1</uniqueid>"));
+        assert!(roxmltree::Document::parse(&nfo).is_ok());
+
+        let nfo = tvshow_nfo(&anime, Some("Jinto & Lafiel <3"));
+        assert!(nfo.contains("<plot>Jinto &amp; Lafiel &lt;3</plot>"));
+        assert!(nfo.contains("<premiered>2000-01-01</premiered>"));
+        assert!(roxmltree::Document::parse(&nfo).is_ok());
+    }
+}
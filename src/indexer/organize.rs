@@ -0,0 +1,628 @@
+//! Sorting identified files into a library laid out by a template. Every run is logged so
+//! it can be undone, and moves update `indexed_files` in the same transaction as the
+//! rename so the index never points at a file that isn't there. Moves to another
+//! filesystem copy the file and remove the original once the copy is on disk.
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Sqlite, Transaction};
+use tokio::fs;
+
+use crate::anidb::records::{Anime, Episode, File, Group};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Move,
+    Hardlink,
+    Symlink,
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Move => write!(f, "move"),
+            Self::Hardlink => write!(f, "hardlink"),
+            Self::Symlink => write!(f, "symlink"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Move {
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub moves: Vec<Move>,
+    /// Moves whose target already exists, or is taken by an earlier move
+    pub conflicts: Vec<Move>,
+}
+
+/// Where the identified files at or below `path` would go under `root`
+pub async fn plan(path: &Path, root: &Path, template: &str) -> Result<Plan> {
+    let utf_path = path.to_string_lossy();
+    let prefix = format!("{}/", utf_path.trim_end_matches('/'));
+    let len = prefix.chars().count() as i64;
+
+    let rows = sqlx::query!(
+        r#"SELECT i.path, f.json as fjson, a.json as ajson, e.json as ejson, g.json as "gjson?"
+           FROM indexed_files i
+           INNER JOIN files f ON i.fid = f.fid
+           INNER JOIN anime a ON f.aid = a.aid
+           INNER JOIN episodes e ON f.eid = e.eid
+           LEFT OUTER JOIN groups g ON f.gid = g.gid
+           WHERE (i.path = ? OR substr(i.path, 1, ?) = ?) AND i.missing_since IS NULL
+           ORDER BY i.path"#,
+        utf_path,
+        len,
+        prefix
+    )
+    .fetch_all(crate::DB.get().await)
+    .await?;
+
+    let mut plan = Plan::default();
+    let mut taken = HashSet::new();
+
+    for row in rows {
+        let source = PathBuf::from(row.path);
+
+        let fields = Fields {
+            anime: record::<Anime>(&row.ajson)?,
+            episode: record::<Episode>(&row.ejson)?,
+            group: match row.gjson {
+                Some(json) => record::<Group>(&json)?,
+                None => serde_json::to_value(Group::default())?,
+            },
+            file: record::<File>(&row.fjson)?,
+            ext: source
+                .extension()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        };
+
+        let target = root.join(render(template, &fields)?);
+
+        if target == source {
+            continue;
+        }
+
+        let planned = Move { source, target };
+        if target_exists(&planned.target) || !taken.insert(planned.target.clone()) {
+            plan.conflicts.push(planned);
+        } else {
+            plan.moves.push(planned);
+        }
+    }
+
+    Ok(plan)
+}
+
+fn record<T: Serialize + for<'de> Deserialize<'de>>(json: &str) -> Result<Value> {
+    let record = serde_json::from_str::<T>(json).context("Invalid record in database")?;
+    Ok(serde_json::to_value(record)?)
+}
+
+fn target_exists(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+struct Fields {
+    anime: Value,
+    episode: Value,
+    group: Value,
+    file: Value,
+    ext: String,
+}
+
+impl Fields {
+    fn get(&self, name: &str) -> Result<String> {
+        if name == "ext" {
+            return Ok(self.ext.clone());
+        }
+
+        let (record, field) = match name.split_once('.') {
+            Some(("anime", field)) => (&self.anime, field),
+            Some(("episode", field)) => (&self.episode, field),
+            Some(("group", field)) => (&self.group, field),
+            Some(("file", field)) => (&self.file, field),
+            _ => bail!("Unknown placeholder {{{name}}}"),
+        };
+
+        let Some(value) = record.get(field) else {
+            bail!("Unknown placeholder {{{name}}}");
+        };
+
+        Ok(match value {
+            Value::String(s) => s.clone(),
+            Value::Array(values) => values
+                .iter()
+                .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Value::Null => String::new(),
+            value => value.to_string(),
+        })
+    }
+}
+
+/// Fill in a template like `{anime.romaji_name}/{episode.epno:2}.{ext}`. Slashes in the
+/// template separate directories, the ones in field values are replaced.
+fn render(template: &str, fields: &Fields) -> Result<PathBuf> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in template: {template}");
+        };
+        let placeholder = &rest[start + 1..start + len];
+
+        let (names, width) = match placeholder.rsplit_once(':') {
+            Some((names, width)) => (
+                names,
+                width
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid padding in {{{placeholder}}}"))?,
+            ),
+            None => (placeholder, 0),
+        };
+
+        let mut value = String::new();
+        for name in names.split('|') {
+            value = fields.get(name.trim())?;
+            if !value.is_empty() {
+                break;
+            }
+        }
+
+        rendered.push_str(&sanitize(&pad(&value, width)));
+        rest = &rest[start + len + 1..];
+    }
+    rendered.push_str(rest);
+
+    let path = PathBuf::from(rendered.trim());
+    let escapes = path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)));
+
+    if escapes || path.as_os_str().is_empty() {
+        bail!("Template produced an invalid path: {}", path.display());
+    }
+
+    Ok(path)
+}
+
+/// Zero-pad the first number in `value`, so `S2` becomes `S02` with a width of 2
+fn pad(value: &str, width: usize) -> String {
+    let Some(start) = value.find(|c: char| c.is_ascii_digit()) else {
+        return value.to_string();
+    };
+    let end = value[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(value.len(), |len| start + len);
+
+    format!("{}{:0>width$}{}", &value[..start], &value[start..end], &value[end..])
+}
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_matches([' ', '.'])
+        .to_string()
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub done: usize,
+    pub failed: Vec<(Move, anyhow::Error)>,
+}
+
+/// Carry out the moves in `plan`, logged as one run that [`undo`] can revert
+pub async fn organize(moves: &[Move], root: &Path, mode: Mode) -> Result<Report> {
+    let db = crate::DB.get().await;
+    let run =
+        sqlx::query_scalar!(r#"SELECT coalesce(max(run), 0) + 1 as "run!: i64" FROM organize_log"#)
+            .fetch_one(db)
+            .await?;
+
+    let mut report = Report::default();
+
+    for planned in moves {
+        match apply(planned, root, mode, run).await {
+            Ok(()) => report.done += 1,
+            Err(e) => report.failed.push((planned.clone(), e)),
+        }
+    }
+
+    Ok(report)
+}
+
+async fn apply(planned: &Move, root: &Path, mode: Mode, run: i64) -> Result<()> {
+    let Move { source, target } = planned;
+
+    if target_exists(target) {
+        bail!("{} already exists", target.display());
+    }
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+
+    let utf_source = source.to_string_lossy();
+    let utf_target = target.to_string_lossy();
+    let utf_root = root.to_string_lossy();
+    let mode_name = mode.to_string();
+
+    let mut tx = crate::DB.get().await.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO organize_log (run, mode, root, source, target) VALUES (?, ?, ?, ?, ?)",
+        run,
+        mode_name,
+        utf_root,
+        utf_source,
+        utf_target
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    match mode {
+        Mode::Move => {
+            move_file(source, target).await?;
+
+            if let Err(e) = update_moved(tx, source, target).await {
+                // put the file back where the database thinks it is
+                move_file(target, source).await?;
+                return Err(e);
+            }
+        }
+        Mode::Hardlink => {
+            fs::hard_link(source, target)
+                .await
+                .with_context(|| format!("Failed to link {}", source.display()))?;
+            tx.commit().await?;
+        }
+        Mode::Symlink => {
+            fs::symlink(source, target)
+                .await
+                .with_context(|| format!("Failed to link {}", source.display()))?;
+            tx.commit().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Revert the last run that hasn't been undone yet. Returns how many files were put back.
+pub async fn undo() -> Result<usize> {
+    let db = crate::DB.get().await;
+
+    let entries = sqlx::query!(
+        "SELECT id, mode, root, source, target FROM organize_log
+         WHERE run = (SELECT max(run) FROM organize_log WHERE undone = 0) AND undone = 0
+         ORDER BY id DESC"
+    )
+    .fetch_all(db)
+    .await?;
+
+    if entries.is_empty() {
+        bail!("Nothing to undo");
+    }
+
+    let mut undone = 0;
+
+    for entry in entries {
+        let source = PathBuf::from(&entry.source);
+        let target = PathBuf::from(&entry.target);
+
+        let mut tx = db.begin().await?;
+
+        sqlx::query!("UPDATE organize_log SET undone = 1 WHERE id = ?", entry.id)
+            .execute(&mut *tx)
+            .await?;
+
+        match entry.mode.as_str() {
+            "move" => {
+                if target_exists(&source) {
+                    log::warn!(
+                        "{} exists again, leaving {} alone",
+                        source.display(),
+                        target.display()
+                    );
+                    tx.commit().await?;
+                    continue;
+                }
+
+                if let Some(parent) = source.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+
+                move_file(&target, &source)
+                    .await
+                    .with_context(|| format!("Failed to move {} back", target.display()))?;
+
+                if let Err(e) = update_moved(tx, &target, &source).await {
+                    move_file(&source, &target).await?;
+                    return Err(e);
+                }
+            }
+            _ => {
+                if is_link_to(&target, &source, &entry.mode) {
+                    fs::remove_file(&target)
+                        .await
+                        .with_context(|| format!("Failed to remove {}", target.display()))?;
+                } else {
+                    log::warn!("{} was changed, leaving it alone", target.display());
+                }
+
+                tx.commit().await?;
+            }
+        }
+
+        remove_empty_dirs(&target, Path::new(&entry.root)).await;
+        undone += 1;
+    }
+
+    Ok(undone)
+}
+
+/// Point the index at a file that was moved and commit the move. A copy to another
+/// filesystem is a new inode, so that's updated too.
+async fn update_moved(mut tx: Transaction<'_, Sqlite>, from: &Path, to: &Path) -> Result<()> {
+    let utf_from = from.to_string_lossy();
+    let utf_to = to.to_string_lossy();
+    let filename = to.file_name().unwrap_or_default().to_string_lossy();
+    let inode = fs::metadata(to).await?.ino() as i64;
+
+    sqlx::query!(
+        "UPDATE indexed_files SET path = ?, filename = ?, inode = ? WHERE path = ?",
+        utf_to,
+        filename,
+        inode,
+        utf_from
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Rename `source` to `target`, or copy it over and remove the original when they're on
+/// different filesystems
+async fn move_file(source: &Path, target: &Path) -> Result<()> {
+    match fs::rename(source, target).await {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        result => {
+            return result.with_context(|| format!("Failed to move {}", source.display()));
+        }
+    }
+
+    let (source, target) = (source.to_owned(), target.to_owned());
+    tokio::task::spawn_blocking(move || copy_across(&source, &target)).await?
+}
+
+fn copy_across(source: &Path, target: &Path) -> Result<()> {
+    let copied = (|| -> io::Result<()> {
+        std::fs::copy(source, target)?;
+
+        // keep the mtime, which is what tells the indexer the file hasn't changed
+        let file = std::fs::File::options().write(true).open(target)?;
+        file.set_modified(source.metadata()?.modified()?)?;
+        file.sync_all()?;
+
+        if let Some(parent) = target.parent() {
+            std::fs::File::open(parent)?.sync_all()?;
+        }
+
+        Ok(())
+    })();
+
+    if let Err(e) = copied {
+        let _ = std::fs::remove_file(target);
+        return Err(e).with_context(|| {
+            format!("Failed to copy {} to {}", source.display(), target.display())
+        });
+    }
+
+    if let Err(e) = std::fs::remove_file(source) {
+        let _ = std::fs::remove_file(target);
+        return Err(e).with_context(|| format!("Failed to remove {}", source.display()));
+    }
+
+    Ok(())
+}
+
+fn is_link_to(link: &Path, source: &Path, mode: &str) -> bool {
+    if mode == "symlink" {
+        return link.read_link().is_ok_and(|to| to == source);
+    }
+
+    match (link.metadata(), source.metadata()) {
+        (Ok(link), Ok(source)) => link.dev() == source.dev() && link.ino() == source.ino(),
+        _ => false,
+    }
+}
+
+/// Clean up the directories a run created, without going above the root
+async fn remove_empty_dirs(file: &Path, root: &Path) {
+    for dir in file.ancestors().skip(1) {
+        if !dir.starts_with(root) || dir == root || fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anidb::{mock::fixtures, records::Record};
+
+    #[tokio::test]
+    async fn organizes_and_undoes() {
+        let db = crate::DB.get().await;
+        let dir = std::env::temp_dir().join(format!("tetsu-organize-{}", std::process::id()));
+        let downloads = dir.join("downloads");
+        let library = dir.join("library");
+        fs::create_dir_all(&downloads).await.unwrap();
+
+        let source = downloads.join("[TF] Seikai no Monshou - 01 [1080p].mkv");
+        fs::write(&source, b"an identified video file")
+            .await
+            .unwrap();
+
+        let anime = serde_json::to_string(&Anime::parse(fixtures::ANIME).unwrap()).unwrap();
+        let episode = serde_json::to_string(&Episode::parse(fixtures::EPISODE).unwrap()).unwrap();
+        let group = serde_json::to_string(&Group::parse(fixtures::GROUP).unwrap()).unwrap();
+        let file = File::parse(&fixtures::file(300, 24, "abcd")).unwrap();
+        let file = serde_json::to_string(&file).unwrap();
+
+        sqlx::query!("INSERT OR REPLACE INTO anime (aid, json) VALUES (1, ?)", anime)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query!("INSERT OR REPLACE INTO episodes (eid, aid, json) VALUES (1, 1, ?)", episode)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO groups (gid, json, name, short)
+             VALUES (1, ?, 'Tetsu Fansubs', 'TF')",
+            group
+        )
+        .execute(db)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO files (fid, aid, eid, gid, size, ed2k, json)
+             VALUES (300, 1, 1, 1, 24, 'abcd', ?)",
+            file
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        let utf_source = source.to_string_lossy();
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, fid, first_seen, last_updated)
+             VALUES (?, 'a.mkv', 24, 'abcd', 300, 0, 0)",
+            utf_source
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        let template =
+            "{anime.english_name|anime.romaji_name}/{episode.epno:2} - {episode.romaji} [{group.short}] [{file.quality}].{ext}";
+        let plan = plan(&downloads, &library, template).await.unwrap();
+        let target = library.join("Crest of the Stars/01 - Shinryaku [TF] [high].mkv");
+        assert_eq!(
+            plan.moves,
+            [Move {
+                source: source.clone(),
+                target: target.clone()
+            }]
+        );
+        assert!(plan.conflicts.is_empty());
+
+        let report = organize(&plan.moves, &library, Mode::Move).await.unwrap();
+        assert_eq!(report.done, 1);
+        assert!(target.exists() && !source.exists());
+
+        let indexed = |path: &Path| {
+            let utf_path = path.to_string_lossy().to_string();
+            async move {
+                sqlx::query_scalar!("SELECT fid FROM indexed_files WHERE path = ?", utf_path)
+                    .fetch_optional(crate::DB.get().await)
+                    .await
+                    .unwrap()
+                    .flatten()
+            }
+        };
+        assert_eq!(indexed(&target).await, Some(300));
+
+        assert_eq!(undo().await.unwrap(), 1);
+        assert!(source.exists() && !target.exists());
+        assert!(!library.join("Crest of the Stars").exists());
+        assert_eq!(indexed(&source).await, Some(300));
+
+        let plan = super::plan(&downloads, &library, "{anime.romaji_name}/{ext}")
+            .await
+            .unwrap();
+        let report = organize(&plan.moves, &library, Mode::Symlink)
+            .await
+            .unwrap();
+        assert_eq!(report.done, 1);
+        let link = library.join("Seikai no Monshou/mkv");
+        assert_eq!(link.read_link().unwrap(), source);
+        assert_eq!(indexed(&source).await, Some(300));
+
+        assert_eq!(undo().await.unwrap(), 1);
+        assert!(!target_exists(&link) && source.exists());
+
+        assert!(render("{anime.nope}", &fields()).is_err());
+        assert!(render("../{ext}", &fields()).is_err());
+        assert_eq!(pad("S2", 2), "S02");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn copies_across_filesystems() {
+        let dir = std::env::temp_dir().join(format!("tetsu-copy-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let source = dir.join("source.mkv");
+        let target = dir.join("target.mkv");
+        fs::write(&source, b"a video file on another disk")
+            .await
+            .unwrap();
+        let mtime = source.metadata().unwrap().modified().unwrap();
+
+        copy_across(&source, &target).unwrap();
+        assert!(!source.exists());
+        assert_eq!(fs::read(&target).await.unwrap(), b"a video file on another disk");
+        assert_eq!(target.metadata().unwrap().modified().unwrap(), mtime);
+
+        // nothing is left behind when the copy fails
+        assert!(copy_across(&source, &dir.join("copy.mkv")).is_err());
+        assert!(!dir.join("copy.mkv").exists() && target.exists());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    fn fields() -> Fields {
+        Fields {
+            anime: Value::Null,
+            episode: Value::Null,
+            group: Value::Null,
+            file: Value::Null,
+            ext: "mkv".to_string(),
+        }
+    }
+}
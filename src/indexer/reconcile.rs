@@ -0,0 +1,271 @@
+//! Keeping `indexed_files` in line with what's actually on disk. Files that moved are
+//! recognized by inode and mtime before hashing, or by size and ed2k after, and keep
+//! their row. Files that disappeared are flagged as missing until [`gc`] prunes them.
+
+use std::{fs::Metadata, os::unix::fs::MetadataExt, path::Path};
+
+use anyhow::Result;
+
+/// What we know about a file that's indexed under its current path
+pub struct Indexed {
+    pub filesize: i64,
+    pub ed2k: String,
+    pub fid: Option<i64>,
+    pub inode: Option<i64>,
+    pub mtime: Option<i64>,
+}
+
+impl Indexed {
+    pub async fn get(path: &Path) -> Result<Option<Self>> {
+        let utf_path = path.to_string_lossy();
+
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT filesize, ed2k, fid, inode, mtime FROM indexed_files WHERE path = ?",
+            utf_path
+        )
+        .fetch_optional(crate::DB.get().await)
+        .await?)
+    }
+
+    /// Whether the file looks untouched since it was hashed. Rows from before we stored
+    /// inodes and mtimes get the benefit of the doubt as long as the size matches. Rows
+    /// with an inode but no mtime have a hash we never checked, so they don't.
+    pub fn unchanged(&self, meta: &Metadata) -> bool {
+        self.filesize == meta.len() as i64
+            && match self.mtime {
+                Some(mtime) => mtime == meta.mtime(),
+                None => self.inode.is_none(),
+            }
+    }
+
+    /// The ed2k AniDB has for the file if it was identified, the one we hashed otherwise
+    pub async fn expected_ed2k(&self) -> Result<String> {
+        let Some(fid) = self.fid else {
+            return Ok(self.ed2k.clone());
+        };
+
+        let anidb_ed2k = sqlx::query_scalar!("SELECT ed2k FROM files WHERE fid = ?", fid)
+            .fetch_optional(crate::DB.get().await)
+            .await?;
+
+        Ok(anidb_ed2k.unwrap_or_else(|| self.ed2k.clone()))
+    }
+}
+
+/// Remember the inode and mtime of a file whose stored hash we just confirmed
+pub async fn touch(path: &Path, meta: &Metadata) -> Result<()> {
+    let utf_path = path.to_string_lossy();
+    let inode = meta.ino() as i64;
+    let mtime = meta.mtime();
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query!(
+        "UPDATE indexed_files
+         SET inode = ?, mtime = ?, missing_since = NULL, last_updated = ?
+         WHERE path = ?",
+        inode,
+        mtime,
+        now,
+        utf_path
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    Ok(())
+}
+
+/// Drop the row of a file that was replaced, so it gets identified from scratch
+pub async fn forget(path: &Path) -> Result<()> {
+    let utf_path = path.to_string_lossy();
+
+    sqlx::query!("DELETE FROM indexed_files WHERE path = ?", utf_path)
+        .execute(crate::DB.get().await)
+        .await?;
+
+    Ok(())
+}
+
+/// Where a file was indexed before it moved, if it did. Without a hash only the inode and
+/// mtime can tell it's the same file, with one the ed2k does.
+pub async fn find_moved(meta: &Metadata, ed2k: Option<&str>) -> Result<Option<String>> {
+    let size = meta.len() as i64;
+    let inode = meta.ino() as i64;
+    let mtime = meta.mtime();
+
+    let candidates =
+        sqlx::query!("SELECT path, ed2k, inode, mtime FROM indexed_files WHERE filesize = ?", size)
+            .fetch_all(crate::DB.get().await)
+            .await?;
+
+    let old = candidates.into_iter().find(|row| {
+        let same = match ed2k {
+            Some(ed2k) => row.ed2k == ed2k,
+            None => row.inode == Some(inode) && row.mtime == Some(mtime),
+        };
+
+        same && !Path::new(&row.path).exists()
+    });
+
+    Ok(old.map(|row| row.path))
+}
+
+/// Move the row of a file that isn't where we last saw it anymore to `path`
+pub async fn relocate(path: &Path, meta: &Metadata, ed2k: Option<&str>) -> Result<bool> {
+    let Some(old_path) = find_moved(meta, ed2k).await? else {
+        return Ok(false);
+    };
+
+    let inode = meta.ino() as i64;
+    let mtime = meta.mtime();
+
+    let utf_path = path.to_string_lossy();
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query!(
+        "UPDATE indexed_files
+         SET path = ?, filename = ?, inode = ?, mtime = ?, missing_since = NULL, last_updated = ?
+         WHERE path = ?",
+        utf_path,
+        filename,
+        inode,
+        mtime,
+        now,
+        old_path
+    )
+    .execute(crate::DB.get().await)
+    .await?;
+
+    log::info!("{} moved to {}", old_path, utf_path);
+
+    Ok(true)
+}
+
+/// Flag indexed files at or below `path` that don't exist anymore, and unflag the ones
+/// that came back. Returns how many are missing.
+pub async fn check_missing(path: &Path) -> Result<usize> {
+    let db = crate::DB.get().await;
+    let utf_path = path.to_string_lossy();
+    let prefix = format!("{}/", utf_path.trim_end_matches('/'));
+    let len = prefix.chars().count() as i64;
+    let now = chrono::Utc::now().timestamp();
+
+    let rows = sqlx::query!(
+        "SELECT path, missing_since FROM indexed_files WHERE path = ? OR substr(path, 1, ?) = ?",
+        utf_path,
+        len,
+        prefix
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut missing = 0;
+
+    for row in rows {
+        let exists = Path::new(&row.path).exists();
+
+        let missing_since = match (exists, row.missing_since) {
+            (true, None) => continue,
+            (true, Some(_)) => None,
+            (false, since) => {
+                missing += 1;
+                Some(since.unwrap_or(now))
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE indexed_files SET missing_since = ? WHERE path = ?",
+            missing_since,
+            row.path
+        )
+        .execute(db)
+        .await?;
+    }
+
+    Ok(missing)
+}
+
+/// Forget files that have been missing since they were last checked. Returns how many
+/// were removed.
+pub async fn gc() -> Result<usize> {
+    let db = crate::DB.get().await;
+
+    let missing =
+        sqlx::query_scalar!("SELECT path FROM indexed_files WHERE missing_since IS NOT NULL")
+            .fetch_all(db)
+            .await?;
+
+    let mut removed = 0;
+
+    for path in missing {
+        if Path::new(&path).exists() {
+            sqlx::query!("UPDATE indexed_files SET missing_since = NULL WHERE path = ?", path)
+                .execute(db)
+                .await?;
+        } else {
+            sqlx::query!("DELETE FROM indexed_files WHERE path = ?", path)
+                .execute(db)
+                .await?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn moved_and_missing_files() {
+        let db = crate::DB.get().await;
+        let dir = std::env::temp_dir().join(format!("tetsu-reconcile-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let path = dir.join("moved.mkv");
+        fs::write(&path, b"a file that was moved").await.unwrap();
+        let meta = path.metadata().unwrap();
+
+        let old_path = dir.join("old/moved.mkv");
+        let old_utf_path = old_path.to_string_lossy();
+        let (size, inode, mtime) = (meta.len() as i64, meta.ino() as i64, meta.mtime());
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, fid, inode, mtime, first_seen, last_updated)
+             VALUES (?, 'moved.mkv', ?, 'abcd', 200, ?, ?, 0, 0)",
+            old_utf_path,
+            size,
+            inode,
+            mtime
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        assert!(!relocate(&path, &meta, Some("other")).await.unwrap());
+        assert!(relocate(&path, &meta, None).await.unwrap());
+
+        let utf_path = path.to_string_lossy();
+        let fid = sqlx::query_scalar!("SELECT fid FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_one(db)
+            .await
+            .unwrap();
+        assert_eq!(fid, Some(200));
+
+        assert_eq!(check_missing(&dir).await.unwrap(), 0);
+        fs::remove_file(&path).await.unwrap();
+        assert_eq!(check_missing(&dir).await.unwrap(), 1);
+
+        assert!(gc().await.unwrap() >= 1);
+        let rows = sqlx::query!("SELECT path FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_all(db)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
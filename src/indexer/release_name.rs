@@ -0,0 +1,281 @@
+//! Identifying files from their name when AniDB doesn't know the hash. Covers the usual
+//! fansub naming, `[Group] Title - 05v2 [1080p][ABCD1234].mkv`, along with dotted and
+//! underscored names and `S01E05` style numbering.
+
+use anyhow::{Context, Result};
+
+use crate::anidb::{records::Episode, titles, Anidb};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReleaseName {
+    pub group: Option<String>,
+    pub title: String,
+    /// Episode number the way AniDB writes it, `5` or `S2` for specials
+    pub episode: Option<String>,
+    pub crc32: Option<String>,
+}
+
+impl ReleaseName {
+    pub fn parse(filename: &str) -> Option<Self> {
+        let stem = match filename.rsplit_once('.') {
+            Some((stem, ext))
+                if (1..=4).contains(&ext.len())
+                    && ext.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                stem
+            }
+            _ => filename,
+        };
+
+        let stem = if stem.contains(' ') {
+            stem.to_string()
+        } else {
+            stem.replace(['_', '.'], " ")
+        };
+
+        let mut release = Self::default();
+
+        // pull out bracketed tags, the first one being the group if nothing comes before it
+        let mut remainder = String::new();
+        let mut rest = stem.as_str();
+        while let Some(start) = rest.find(['[', '(']) {
+            let close = if rest[start..].starts_with('[') {
+                ']'
+            } else {
+                ')'
+            };
+            let Some(len) = rest[start..].find(close) else {
+                break;
+            };
+
+            let tag = rest[start + 1..start + len].trim();
+            remainder.push_str(&rest[..start]);
+
+            if release.group.is_none() && remainder.trim().is_empty() {
+                release.group = Some(tag.to_string());
+            } else if tag.len() == 8 && tag.chars().all(|c| c.is_ascii_hexdigit()) {
+                release.crc32 = Some(tag.to_ascii_uppercase());
+            }
+
+            remainder.push(' ');
+            rest = &rest[start + len + 1..];
+        }
+        remainder.push_str(rest);
+
+        let words = remainder.split_whitespace().collect::<Vec<_>>();
+
+        // an episode number right after a dash is a safer bet than whatever number comes last
+        let after_dash = (1..words.len())
+            .find(|&i| words[i - 1] == "-" && episode_number(words[i], true).is_some());
+        let position = after_dash.or_else(|| {
+            (0..words.len())
+                .rev()
+                .find(|&i| episode_number(words[i], false).is_some())
+        });
+
+        let mut title_words = match position {
+            Some(i) => {
+                release.episode = episode_number(words[i], true);
+                &words[..i]
+            }
+            None => &words[..],
+        };
+
+        while let [init @ .., "-"] = title_words {
+            title_words = init;
+        }
+
+        release.title = title_words.join(" ");
+
+        (!release.title.is_empty()).then_some(release)
+    }
+}
+
+/// `05`, `05v2`, `E05`, `S01E05` or `SP2`, normalized to what AniDB uses for epno. Lone
+/// four-digit numbers look more like a year than an episode unless we know better.
+fn episode_number(word: &str, after_dash: bool) -> Option<String> {
+    let word = match word.rsplit_once(['v', 'V']) {
+        Some((number, version)) if version.chars().all(|c| c.is_ascii_digit()) => number,
+        _ => word,
+    };
+
+    let upper = word.to_ascii_uppercase();
+    let (prefix, number) = if let Some(number) = upper.strip_prefix("SP") {
+        ("S", number)
+    } else if let Some((_, number)) = upper.split_once('E').filter(|(season, _)| {
+        season.is_empty()
+            || season
+                .strip_prefix('S')
+                .is_some_and(|s| s.chars().all(|c| c.is_ascii_digit()))
+    }) {
+        ("", number.strip_prefix("P").unwrap_or(number))
+    } else {
+        ("", upper.as_str())
+    };
+
+    if number.is_empty() || number.len() > 4 || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let number = number.parse::<u32>().ok()?;
+    if prefix.is_empty() && word.len() == 4 && (1900..2100).contains(&number) && !after_dash {
+        return None;
+    }
+
+    Some(format!("{prefix}{number}"))
+}
+
+/// A tentative identification. Confidence goes from 0 to 1: a title match on its own is
+/// worth 0.6 when exact and 0.3 otherwise, a cached episode with the right number adds 0.3
+/// and a known group 0.1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Guess {
+    pub release: ReleaseName,
+    pub aid: u32,
+    pub eid: Option<u32>,
+    pub confidence: f64,
+}
+
+pub async fn guess(anidb: &mut Anidb, filename: &str) -> Result<Option<Guess>> {
+    let Some(release) = ReleaseName::parse(filename) else {
+        return Ok(None);
+    };
+
+    let Some((aid, mut confidence)) = match_title(&release.title).await? else {
+        return Ok(None);
+    };
+
+    // having the anime cached is what makes the file show up in the library
+    if anidb
+        .anime_by_aid(aid)
+        .await
+        .context("Failed to get anime data from AniDB")?
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    let eid = match release.episode {
+        Some(ref epno) => match_episode(aid, epno).await?,
+        None => None,
+    };
+
+    if eid.is_some() {
+        confidence += 0.3;
+    }
+
+    if let Some(ref group) = release.group {
+        if known_group(group).await? {
+            confidence += 0.1;
+        }
+    }
+
+    Ok(Some(Guess {
+        release,
+        aid,
+        eid,
+        confidence: confidence.min(1.),
+    }))
+}
+
+fn normalize(title: &str) -> String {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// cached anime are added to the title index too, so this works without the title dump
+async fn match_title(title: &str) -> Result<Option<(u32, f64)>> {
+    let wanted = normalize(title);
+    let matches = titles::search(title, 10).await?;
+
+    if let Some(exact) = matches
+        .iter()
+        .find(|m| normalize(&m.title) == wanted || normalize(&m.main_title) == wanted)
+    {
+        return Ok(Some((exact.aid, 0.6)));
+    }
+
+    Ok(matches.first().map(|m| (m.aid, 0.3)))
+}
+
+async fn match_episode(aid: u32, epno: &str) -> Result<Option<u32>> {
+    let cached = sqlx::query_scalar!("SELECT json FROM episodes WHERE aid = ?", aid)
+        .fetch_all(crate::DB.get().await)
+        .await?;
+
+    for json in cached {
+        let episode =
+            serde_json::from_str::<Episode>(&json).context("Invalid record in database")?;
+
+        if episode.has_epno(epno) {
+            return Ok(Some(episode.eid));
+        }
+    }
+
+    Ok(None)
+}
+
+async fn known_group(name: &str) -> Result<bool> {
+    let known = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM groups WHERE name = $1 COLLATE NOCASE OR short = $1 COLLATE NOCASE
+        ) AS "known!: bool""#,
+        name
+    )
+    .fetch_one(crate::DB.get().await)
+    .await?;
+
+    Ok(known)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(
+        group: Option<&str>,
+        title: &str,
+        episode: Option<&str>,
+        crc32: Option<&str>,
+    ) -> ReleaseName {
+        ReleaseName {
+            group: group.map(str::to_string),
+            title: title.to_string(),
+            episode: episode.map(str::to_string),
+            crc32: crc32.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn release_names() {
+        let cases = [
+            (
+                "[TF] Seikai no Monshou - 01 [1080p][abcd1234].mkv",
+                release(Some("TF"), "Seikai no Monshou", Some("1"), Some("ABCD1234")),
+            ),
+            (
+                "[TF] Seikai no Monshou - 05v2 (BD 1080p) - The Finale 2 [DEADBEEF].mkv",
+                release(Some("TF"), "Seikai no Monshou", Some("5"), Some("DEADBEEF")),
+            ),
+            (
+                "Seikai.no.Monshou.S01E03.1080p.BluRay.mkv",
+                release(None, "Seikai no Monshou", Some("3"), None),
+            ),
+            (
+                "[TF]_Seikai_no_Monshou_SP2_[720p].mkv",
+                release(Some("TF"), "Seikai no Monshou", Some("S2"), None),
+            ),
+            ("[TF] Some Movie (2011) [1080p].mkv", release(Some("TF"), "Some Movie", None, None)),
+        ];
+
+        for (filename, expected) in cases {
+            assert_eq!(ReleaseName::parse(filename), Some(expected), "{filename}");
+        }
+
+        assert_eq!(ReleaseName::parse("[TF] [1080p].mkv"), None);
+    }
+}
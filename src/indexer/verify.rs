@@ -0,0 +1,184 @@
+//! Checking files on disk against every hash we know for them: the ones AniDB has for the
+//! identified file, the ones we stored when it was indexed, and the CRC32 release groups
+//! like to put in the filename.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use super::{ed2k, filter::FileFilter, release_name::ReleaseName, walk, IndexOptions};
+use crate::anidb::records::File;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    /// Where the expected hash came from, e.g. `AniDB MD5` or `filename CRC32`
+    pub source: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checked: usize,
+    /// Files there was nothing to compare against
+    pub unknown: Vec<PathBuf>,
+    pub mismatches: Vec<Mismatch>,
+    /// Files that couldn't be read
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Hash `path`, or every file below it the index filters allow, and compare against what
+/// we know
+pub async fn verify(path: &Path, hasher: ed2k::Hasher) -> Result<Report> {
+    let config = crate::CONFIG.read().await.index.clone();
+    let filter = FileFilter::new(path, &config, &IndexOptions::default())?;
+    let files = walk(path, &filter).await;
+
+    let mut report = Report::default();
+
+    for file in files {
+        // no point reading the whole file if there's nothing to compare it to
+        let expected = expected_hashes(&file).await?;
+        if expected.is_empty() {
+            report.unknown.push(file);
+            continue;
+        }
+
+        let hashes = {
+            let path = file.clone();
+            tokio::task::spawn_blocking(move || {
+                hasher.hash_file_with(&path, &ProgressBar::hidden(), true)
+            })
+            .await?
+        };
+
+        let hashes = match hashes {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                report.failed.push((file, e.context("Failed to hash file")));
+                continue;
+            }
+        };
+
+        report.checked += 1;
+
+        for (origin, name, expected) in expected {
+            let actual = match name {
+                "ed2k" => &hashes.ed2k,
+                "CRC32" => hashes.crc32.as_ref().unwrap(),
+                "MD5" => hashes.md5.as_ref().unwrap(),
+                _ => hashes.sha1.as_ref().unwrap(),
+            };
+
+            if !actual.eq_ignore_ascii_case(&expected) {
+                report.mismatches.push(Mismatch {
+                    path: file.clone(),
+                    source: format!("{origin} {name}"),
+                    expected: expected.to_ascii_lowercase(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Every hash we have for a file as `(origin, hash name, hash)`. AniDB's take wins over
+/// our own, since ours might have been computed after the file went bad.
+async fn expected_hashes(path: &Path) -> Result<Vec<(&'static str, &'static str, String)>> {
+    let utf_path = path.to_string_lossy();
+    let mut expected = vec![];
+
+    let indexed = sqlx::query!(
+        "SELECT i.ed2k, i.crc32, i.md5, i.sha1, f.json as \"fjson?\"
+         FROM indexed_files i
+         LEFT OUTER JOIN files f ON i.fid = f.fid
+         WHERE i.path = ?",
+        utf_path
+    )
+    .fetch_optional(crate::DB.get().await)
+    .await?;
+
+    if let Some(indexed) = indexed {
+        let anidb = match indexed.fjson {
+            Some(json) => {
+                serde_json::from_str::<File>(&json).context("Invalid record in database")?
+            }
+            None => File::default(),
+        };
+
+        let hashes = [
+            ("ed2k", anidb.ed2k, Some(indexed.ed2k)),
+            ("CRC32", anidb.crc32, indexed.crc32),
+            ("MD5", anidb.md5, indexed.md5),
+            ("SHA1", anidb.sha1, indexed.sha1),
+        ];
+
+        for (name, anidb, ours) in hashes {
+            if !anidb.is_empty() {
+                expected.push(("AniDB", name, anidb));
+            } else if let Some(ours) = ours {
+                expected.push(("indexed", name, ours));
+            }
+        }
+    }
+
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    if let Some(crc32) = ReleaseName::parse(&filename).and_then(|r| r.crc32) {
+        expected.push(("filename", "CRC32", crc32));
+    }
+
+    Ok(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_mismatches() {
+        let dir = std::env::temp_dir().join(format!("tetsu-verify-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        // CRC32 of the contents is 414fa339
+        let contents = b"The quick brown fox jumps over the lazy dog";
+        let good = dir.join("[TF] Seikai no Monshou - 01 [414FA339].mkv");
+        let bad = dir.join("[TF] Seikai no Monshou - 02 [DEADBEEF].mkv");
+        let unknown = dir.join("[TF] Seikai no Monshou - 03.mkv");
+        let notes = dir.join("notes.txt");
+        for path in [&good, &bad, &unknown, &notes] {
+            fs::write(path, contents).await.unwrap();
+        }
+
+        let utf_path = bad.to_string_lossy();
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, md5, first_seen, last_updated)
+             VALUES (?, 'bad.mkv', 43, 'abcd', '9e107d9d372bb6826bd81d3542a419d6', 0, 0)",
+            utf_path
+        )
+        .execute(crate::DB.get().await)
+        .await
+        .unwrap();
+
+        let report = verify(&dir, ed2k::Hasher::Read).await.unwrap();
+
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.unknown, vec![unknown]);
+
+        let mut sources = report
+            .mismatches
+            .iter()
+            .inspect(|m| assert_eq!(m.path, bad))
+            .map(|m| m.source.as_str())
+            .collect::<Vec<_>>();
+        sources.sort();
+        assert_eq!(sources, ["filename CRC32", "indexed ed2k"]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
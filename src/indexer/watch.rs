@@ -0,0 +1,289 @@
+//! Keeping a directory indexed as files come and go. New files are only picked up once
+//! nothing has touched them for a while, so downloads aren't hashed halfway through.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    EventKind, RecursiveMode, Watcher,
+};
+use tokio::{sync::mpsc, time::Instant};
+
+use super::{
+    failures, filter::FileFilter, index_files, progress_bars, reconcile, walk, IndexOptions,
+};
+
+/// How long a file has to be left alone before we consider it complete
+#[cfg(not(test))]
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Watch `path` until the watcher itself stops. Files that can't be indexed are logged and
+/// recorded for `--retry-failed`. Doesn't index what's already there, run [`super::index`]
+/// first for that.
+pub async fn watch(path: &Path, options: &IndexOptions) -> Result<()> {
+    let config = crate::CONFIG.read().await.index.clone();
+    let filter = FileFilter::new(path, &config, options)?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })
+    .context("Failed to start file watcher")?;
+
+    watcher
+        .watch(path, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", path.display()))?;
+
+    log::info!("Watching {} for new files", path.display());
+
+    // paths that changed, and when they last did
+    let mut pending = HashMap::<PathBuf, Instant>::new();
+    let mut tick = tokio::time::interval(SETTLE_TIME / 2);
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        log::warn!("File watcher failed: {e}");
+                        continue;
+                    }
+                };
+
+                if event.need_rescan() {
+                    pending.insert(path.to_owned(), Instant::now());
+                }
+
+                match event.kind {
+                    // the separate From and To events that come with this one are harmless,
+                    // by the time they settle the database already has the new path
+                    EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                        if let [from, to] = &event.paths[..] {
+                            if let Err(e) = moved(from, to).await {
+                                log::error!(
+                                    "Failed to follow {} to {}: {e:#}",
+                                    from.display(),
+                                    to.display()
+                                );
+                            }
+                        }
+                    }
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+                        for path in event.paths {
+                            pending.insert(path, Instant::now());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ = tick.tick() => {
+                let mut settled = vec![];
+                pending.retain(|path, changed| {
+                    let done = changed.elapsed() >= SETTLE_TIME;
+                    if done {
+                        settled.push(path.clone());
+                    }
+                    !done
+                });
+
+                if !settled.is_empty() {
+                    if let Err(e) = process(settled, &filter, options).await {
+                        log::error!("Failed to index changes in {}: {e:#}", path.display());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn process(paths: Vec<PathBuf>, filter: &FileFilter, options: &IndexOptions) -> Result<()> {
+    let mut files = vec![];
+
+    for path in paths {
+        if path.is_dir() {
+            if filter.reaches(&path) {
+                files.extend(walk(&path, filter).await);
+            }
+        } else if path.is_file() {
+            if filter.allows(&path) {
+                files.push(path);
+            }
+        } else if let Err(e) = reconcile::check_missing(&path).await {
+            failed(&path, e).await;
+        }
+    }
+
+    let mut new = vec![];
+
+    for file in files {
+        match needs_indexing(&file).await {
+            Ok(true) => new.push(file),
+            Ok(false) => (),
+            Err(e) => failed(&file, e).await,
+        }
+    }
+
+    if new.is_empty() {
+        return Ok(());
+    }
+
+    let (mpb, overall) = progress_bars();
+    overall.inc_length(new.len() as u64);
+
+    index_files(&mpb, &overall, new, options).await
+}
+
+/// Whether `file` is new or has changed since it was indexed
+async fn needs_indexing(file: &Path) -> Result<bool> {
+    let utf_path = file.to_string_lossy();
+    let indexed =
+        sqlx::query_scalar!("SELECT filesize FROM indexed_files WHERE path = ?", utf_path)
+            .fetch_optional(crate::DB.get().await)
+            .await?;
+
+    let size = match file.metadata() {
+        Ok(meta) => meta.len() as i64,
+        // gone again, its removal is handled once that settles
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context("Failed to read metadata"),
+    };
+
+    match indexed {
+        Some(indexed_size) if indexed_size == size => Ok(false),
+        // overwritten, or indexed by a full scan while it was still being written
+        Some(_) => reconcile::forget(file).await.map(|_| true),
+        None => Ok(true),
+    }
+}
+
+/// Log a file the watcher couldn't deal with and keep it for `--retry-failed`
+async fn failed(path: &Path, e: anyhow::Error) {
+    log::warn!("{}: {e:#}", path.display());
+
+    if let Err(e) = failures::record(path, &e).await {
+        log::error!("Failed to record the failure of {}: {e:#}", path.display());
+    }
+}
+
+/// Follow a file or directory that was renamed or moved within the watched directory
+async fn moved(from: &Path, to: &Path) -> Result<()> {
+    let db = crate::DB.get().await;
+    let from_str = from.to_string_lossy();
+    let to_str = to.to_string_lossy();
+    let filename = to.file_name().unwrap_or_default().to_string_lossy();
+
+    sqlx::query!(
+        "UPDATE indexed_files SET path = ?, filename = ? WHERE path = ?",
+        to_str,
+        filename,
+        from_str
+    )
+    .execute(db)
+    .await?;
+
+    let from_prefix = format!("{from_str}/");
+    let to_prefix = format!("{to_str}/");
+    let len = from_prefix.chars().count() as i64;
+    let rest = len + 1;
+
+    sqlx::query!(
+        "UPDATE indexed_files SET path = ? || substr(path, ?) WHERE substr(path, 1, ?) = ?",
+        to_prefix,
+        rest,
+        len,
+        from_prefix
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs;
+
+    use super::*;
+
+    /// Indexed paths under `dir` that aren't missing, relative to it
+    async fn indexed(dir: &Path) -> Vec<String> {
+        let prefix = format!("{}/%", dir.display());
+        sqlx::query_scalar!(
+            "SELECT path FROM indexed_files
+             WHERE path LIKE ? AND missing_since IS NULL
+             ORDER BY path",
+            prefix
+        )
+        .fetch_all(crate::DB.get().await)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|path| path[dir.as_os_str().len() + 1..].to_string())
+        .collect()
+    }
+
+    async fn wait_for(dir: &Path, expected: &[&str]) {
+        for _ in 0..50 {
+            if indexed(dir).await == expected {
+                return;
+            }
+            tokio::time::sleep(SETTLE_TIME).await;
+        }
+
+        assert_eq!(indexed(dir).await, expected);
+    }
+
+    #[tokio::test]
+    async fn follows_renames_and_deletes() {
+        let dir = std::env::temp_dir().join(format!("tetsu-watch-{}", std::process::id()));
+        fs::create_dir_all(dir.join("season 1")).await.unwrap();
+
+        let path = dir.join("season 1/a.mkv");
+        fs::write(&path, b"a video file").await.unwrap();
+
+        let utf_path = path.to_string_lossy();
+        sqlx::query!(
+            "INSERT INTO indexed_files (path, filename, filesize, ed2k, first_seen, last_updated)
+             VALUES (?, 'a.mkv', 12, 'abcd', 0, 0)",
+            utf_path
+        )
+        .execute(crate::DB.get().await)
+        .await
+        .unwrap();
+
+        let watcher = {
+            let dir = dir.clone();
+            tokio::spawn(async move { watch(&dir, &IndexOptions::default()).await })
+        };
+        tokio::time::sleep(SETTLE_TIME).await;
+
+        fs::rename(&path, dir.join("season 1/b.mkv")).await.unwrap();
+        wait_for(&dir, &["season 1/b.mkv"]).await;
+
+        fs::rename(dir.join("season 1"), dir.join("season 2"))
+            .await
+            .unwrap();
+        wait_for(&dir, &["season 2/b.mkv"]).await;
+
+        fs::remove_dir_all(dir.join("season 2")).await.unwrap();
+        wait_for(&dir, &[]).await;
+
+        watcher.abort();
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::sync::RwLock as StdRwLock;
 
-use anidb::Anidb;
+use anidb::{Anidb, Priority};
 use async_once::AsyncOnce;
 use lazy_static::lazy_static;
 use sqlx::SqlitePool;
@@ -27,6 +27,6 @@ pub mod ui;
 lazy_static! {
     pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::read());
     pub static ref DB: AsyncOnce<SqlitePool> = AsyncOnce::new(db::init());
-    pub static ref ANIDB: RwLock<Anidb> = RwLock::new(Anidb::new());
+    pub static ref ANIDB: RwLock<Anidb> = RwLock::new(Anidb::new().with_priority(Priority::Bulk));
     pub static ref PROGRESS_BAR: StdRwLock<Option<indicatif::MultiProgress>> = StdRwLock::new(None);
 }
@@ -4,6 +4,7 @@ use anyhow::Result;
 use clap::{Parser, ValueEnum};
 use env_logger::Target;
 use tetsu::{log_proxy::LogProxy, *};
+use tokio::task::JoinHandle;
 
 #[derive(Parser)]
 #[clap(version, author, about)]
@@ -24,15 +25,149 @@ enum Subcommand {
     /// Index a directory of anime files
     Index {
         /// Folder or file to index
-        path: PathBuf,
+        #[clap(required_unless_present = "retry_failed")]
+        path: Option<PathBuf>,
+
+        /// Only index files earlier runs failed on, those below `path` if given
+        #[clap(long, conflicts_with = "watch")]
+        retry_failed: bool,
+
+        /// Show what would be hashed and how long AniDB would take, without doing it
+        #[clap(short = 'n', long, conflicts_with_all = ["retry_failed", "watch", "write_playlist", "json_dump"])]
+        dry_run: bool,
 
         /// Write a .m3u8 playlist file
-        #[clap(short, long)]
+        #[clap(short, long, requires = "path")]
         write_playlist: Option<PathBuf>,
 
         /// Dump AniDB data to a JSON file
-        #[clap(short, long)]
+        #[clap(short, long, requires = "path")]
         json_dump: Option<PathBuf>,
+
+        /// Add identified files to your AniDB mylist
+        #[clap(short = 'm', long)]
+        add_to_mylist: bool,
+
+        /// Re-hash every file and report the ones that no longer match AniDB
+        #[clap(long)]
+        verify: bool,
+
+        /// How to read files for hashing, overriding the config
+        #[clap(long)]
+        hasher: Option<indexer::ed2k::Hasher>,
+
+        /// Also compute CRC32, MD5 and SHA1 for `tetsu verify`
+        #[clap(long)]
+        extra_hashes: bool,
+
+        /// Only index files matching these globs, instead of the ones from the config
+        #[clap(long, value_name = "GLOB")]
+        include: Option<Vec<String>>,
+
+        /// Skip files matching these globs, on top of the ones from the config
+        #[clap(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Comma-separated extensions to index instead of the ones from the config, empty for
+        /// any
+        #[clap(long, value_delimiter = ',')]
+        extensions: Option<Vec<String>>,
+
+        /// Keep running and index new files as they appear
+        #[clap(short = 'W', long)]
+        watch: bool,
+    },
+
+    /// Tell tetsu which episode an indexed file is when AniDB doesn't know it
+    Link {
+        path: PathBuf,
+
+        #[clap(long)]
+        aid: u32,
+
+        /// Episode number as AniDB writes it, e.g. 5 or S1
+        #[clap(long)]
+        epno: String,
+
+        #[clap(long)]
+        gid: Option<u32>,
+    },
+
+    /// Forget indexed files that have gone missing
+    Gc,
+
+    /// Sort identified files into a library laid out by a template
+    Organize {
+        /// Folder or file whose indexed files to organize
+        #[clap(required_unless_present = "undo")]
+        path: Option<PathBuf>,
+
+        /// Library root, overriding the config
+        #[clap(long)]
+        to: Option<PathBuf>,
+
+        /// Path template, overriding the config
+        #[clap(long)]
+        template: Option<String>,
+
+        /// Move, hardlink or symlink files, overriding the config
+        #[clap(long)]
+        mode: Option<indexer::organize::Mode>,
+
+        /// Only show where files would go
+        #[clap(short, long)]
+        preview: bool,
+
+        /// Revert the last run
+        #[clap(long, conflicts_with_all = ["path", "preview"])]
+        undo: bool,
+    },
+
+    /// Check files against their AniDB hashes and the CRC32 in their name
+    Verify {
+        /// Folder or file to verify
+        path: PathBuf,
+
+        /// How to read files for hashing, overriding the config
+        #[clap(long)]
+        hasher: Option<indexer::ed2k::Hasher>,
+    },
+
+    /// Manage your AniDB mylist
+    Mylist {
+        #[clap(subcommand)]
+        command: MylistCommand,
+    },
+
+    /// Write metadata for other programs
+    Export {
+        #[clap(subcommand)]
+        command: ExportCommand,
+    },
+
+    /// Index a library from a --json-dump written on another machine, without asking AniDB
+    ImportDump {
+        json: PathBuf,
+
+        /// Folder the library is in on this machine
+        #[clap(long)]
+        root: PathBuf,
+
+        /// Match files by their ed2k instead of their path and size
+        #[clap(long)]
+        rehash: bool,
+    },
+
+    /// Import an AniDB anime-titles dump (.dat or .xml, optionally gzipped) for offline search
+    ImportTitles { path: PathBuf },
+
+    /// Search anime titles offline
+    Search {
+        query: Vec<String>,
+
+        /// Maximum number of results
+        #[clap(short, long, default_value_t = 20)]
+        limit: u32,
     },
 
     /// Run the TUI
@@ -46,6 +181,28 @@ enum Subcommand {
     RemoteGui { addr: String },
 }
 
+#[derive(Parser)]
+enum MylistCommand {
+    /// Add indexed files to the mylist and mark missing ones as deleted
+    Sync,
+}
+
+#[derive(Parser)]
+enum ExportCommand {
+    /// Write Kodi/Jellyfin .nfo files and posters next to the indexed files in a directory
+    Nfo { dir: PathBuf },
+
+    /// Dump every indexed file, cached AniDB record, watch progress and platform link
+    Dump {
+        path: PathBuf,
+
+        /// Write one record per line as they're read, for libraries too big to hold in
+        /// memory
+        #[clap(long)]
+        ndjson: bool,
+    },
+}
+
 #[derive(Clone, Copy, ValueEnum)]
 enum ServerType {
     Tarpc,
@@ -69,6 +226,29 @@ async fn main() -> Result<()> {
             anichart::linker::run().await;
         });
 
+        tokio::spawn(async move {
+            let index = CONFIG.read().await.index.clone();
+            let options = indexer::IndexOptions {
+                add_to_mylist: index.add_to_mylist,
+                ..Default::default()
+            };
+
+            for dir in index.watch {
+                let options = options.clone();
+
+                tokio::spawn(async move {
+                    let res = async {
+                        indexer::index(&dir, &options).await?;
+                        indexer::watch::watch(&dir, &options).await
+                    };
+
+                    if let Err(e) = res.await {
+                        log::error!("Stopped watching {}: {}", dir.display(), e);
+                    }
+                });
+            }
+        });
+
         tokio::spawn(async move {
             let res = match stype {
                 ServerType::Tarpc => server::run().await,
@@ -82,20 +262,228 @@ async fn main() -> Result<()> {
         })
     });
 
+    let res = run(&args, server_handle).await;
+
+    // free the AniDB session instead of leaving it to time out
+    if let Err(e) = ANIDB.write().await.logout().await {
+        log::warn!("Failed to log out of AniDB: {}", e);
+    }
+
+    res
+}
+
+async fn run(args: &Args, server_handle: Option<JoinHandle<()>>) -> Result<()> {
     match &args.subcommand {
         None if args.server.is_some() => {}
         Some(Subcommand::Login) => {
             anidb::login().await?;
         }
-        Some(Subcommand::Index { path, write_playlist, json_dump }) => {
-            indexer::index(path).await?;
+        Some(Subcommand::Index {
+            path,
+            write_playlist,
+            json_dump,
+            add_to_mylist,
+            verify,
+            hasher,
+            extra_hashes,
+            include,
+            exclude,
+            extensions,
+            retry_failed,
+            dry_run,
+            watch,
+        }) => {
+            let options = indexer::IndexOptions {
+                add_to_mylist: *add_to_mylist,
+                verify: *verify,
+                hasher: *hasher,
+                extra_hashes: *extra_hashes,
+                include: include.clone(),
+                exclude: exclude.clone(),
+                extensions: extensions.clone(),
+            };
 
-            if let Some(playlist) = write_playlist {
+            if let (Some(path), true) = (path, *dry_run) {
+                print_plan(&indexer::dry_run::dry_run(path, &options).await?);
+                return Ok(());
+            }
+
+            match path {
+                Some(path) if !*retry_failed => indexer::index(path, &options).await?,
+                path => indexer::retry_failed(path.as_deref(), &options).await?,
+            }
+
+            if let (Some(path), Some(playlist)) = (path, write_playlist) {
                 indexer::playlist::write(path, playlist).await?;
             }
 
-            if let Some(json_path) = json_dump {
-                indexer::dump::dump_json(path, json_path).await?;
+            if let (Some(path), Some(json_path)) = (path, json_dump) {
+                let summary =
+                    indexer::dump::dump_json(indexer::dump::Scope::Folder(path), json_path, false)
+                        .await?;
+                print_dump_summary(&summary);
+            }
+
+            if let (Some(path), true) = (path, *watch) {
+                tokio::select! {
+                    res = indexer::watch::watch(path, &options) => res?,
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+            }
+        }
+        Some(Subcommand::Link { path, aid, epno, gid }) => {
+            let episode =
+                indexer::link::link(&mut *ANIDB.write().await, path, *aid, epno, *gid).await?;
+            println!("Linked {} to episode {} ({})", path.display(), episode.epno, episode.romaji);
+        }
+        Some(Subcommand::Gc) => {
+            let removed = indexer::reconcile::gc().await?;
+            println!("Removed {removed} missing files");
+        }
+        Some(Subcommand::Organize { undo: true, .. }) => {
+            let undone = indexer::organize::undo().await?;
+            println!("Put back {undone} files");
+        }
+        Some(Subcommand::Organize {
+            path,
+            to,
+            template,
+            mode,
+            preview,
+            undo: false,
+        }) => {
+            let config = CONFIG.read().await.organize.clone();
+            let path = path.as_ref().unwrap();
+            let template = template.as_ref().unwrap_or(&config.template);
+            let mode = mode.unwrap_or(config.mode);
+
+            let Some(mut root) = to.clone().or(config.root) else {
+                anyhow::bail!("No library root, pass --to or set organize.root in the config");
+            };
+            if let Ok(rest) = root.strip_prefix("~") {
+                root = PathBuf::from(std::env::var("HOME")?).join(rest);
+            }
+
+            let plan = indexer::organize::plan(path, &root, template).await?;
+
+            for conflict in &plan.conflicts {
+                println!(
+                    "{} -> {} (already taken, skipping)",
+                    conflict.source.display(),
+                    conflict.target.display()
+                );
+            }
+
+            if *preview {
+                for planned in &plan.moves {
+                    println!("{} -> {}", planned.source.display(), planned.target.display());
+                }
+                println!("Would {mode} {} files", plan.moves.len());
+                return Ok(());
+            }
+
+            let report = indexer::organize::organize(&plan.moves, &root, mode).await?;
+
+            for (planned, e) in &report.failed {
+                println!("{}: {e:#}", planned.source.display());
+            }
+
+            println!(
+                "Organized {} files, {} failed, {} skipped. `tetsu organize --undo` reverts this",
+                report.done,
+                report.failed.len(),
+                plan.conflicts.len()
+            );
+        }
+        Some(Subcommand::Verify { path, hasher }) => {
+            let hasher = hasher.unwrap_or(CONFIG.read().await.index.hasher);
+            let report = indexer::verify::verify(path, hasher).await?;
+
+            for mismatch in &report.mismatches {
+                println!(
+                    "{}: {} is {}, got {}",
+                    mismatch.path.display(),
+                    mismatch.source,
+                    mismatch.expected,
+                    mismatch.actual
+                );
+            }
+
+            for path in &report.unknown {
+                log::info!("Nothing to verify {} against", path.display());
+            }
+
+            for (path, e) in &report.failed {
+                println!("{}: {e:#}", path.display());
+            }
+
+            println!(
+                "Checked {} files, {} mismatches, {} without known hashes, {} unreadable",
+                report.checked,
+                report.mismatches.len(),
+                report.unknown.len(),
+                report.failed.len()
+            );
+        }
+        Some(Subcommand::Mylist { command: MylistCommand::Sync }) => {
+            let report = indexer::mylist::sync(&mut *ANIDB.write().await).await?;
+
+            for fid in &report.unknown {
+                println!("File {fid} is not known to AniDB");
+            }
+
+            println!(
+                "Mylist synced: {} files on disk, {} newly added, {} marked as deleted, {} \
+                 removed on AniDB",
+                report.on_disk, report.added, report.removed, report.forgotten
+            );
+        }
+        Some(Subcommand::Export { command: ExportCommand::Nfo { dir } }) => {
+            let image_cache = indexer::nfo::image_cache_dir(&CONFIG.read().await.anidb)?;
+            let report = indexer::nfo::export(dir, &image_cache).await?;
+            println!(
+                "Wrote {} tvshow.nfo, {} episode .nfo files and {} posters, {} pictures weren't cached",
+                report.shows, report.episodes, report.posters, report.missing_posters
+            );
+        }
+        Some(Subcommand::Export {
+            command: ExportCommand::Dump { path, ndjson },
+        }) => {
+            let summary =
+                indexer::dump::dump_json(indexer::dump::Scope::Library, path, *ndjson).await?;
+            print_dump_summary(&summary);
+        }
+        Some(Subcommand::ImportDump { json, root, rehash }) => {
+            let report = indexer::import::import_dump(json, root, *rehash).await?;
+
+            for path in &report.unmatched {
+                log::warn!("No file found for {}", path.display());
+            }
+
+            println!(
+                "Imported {} anime, {} episodes, {} groups, {} watch progress and {} platform \
+                 links, indexed {} files ({} to be checked on the next scan), {} not found",
+                report.anime,
+                report.episodes,
+                report.groups,
+                report.watch_progress,
+                report.platform_links,
+                report.matched,
+                report.unverified,
+                report.unmatched.len()
+            );
+        }
+        Some(Subcommand::ImportTitles { path }) => {
+            let count = anidb::titles::import(path).await?;
+            println!("Imported {count} titles");
+        }
+        Some(Subcommand::Search { query, limit }) => {
+            for anime in anidb::titles::search(&query.join(" "), *limit).await? {
+                if anime.title == anime.main_title {
+                    println!("{:>6}  {}", anime.aid, anime.main_title);
+                } else {
+                    println!("{:>6}  {} ({})", anime.aid, anime.main_title, anime.title);
+                }
             }
         }
         None | Some(Subcommand::Tui) => {
@@ -114,7 +502,10 @@ async fn main() -> Result<()> {
     }
 
     if let Some(handle) = server_handle {
-        let res = handle.await;
+        let res = tokio::select! {
+            res = handle => res,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
 
         if let Err(e) = res {
             if !e.is_cancelled() {
@@ -125,3 +516,46 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn print_dump_summary(summary: &indexer::dump::Summary) {
+    for error in &summary.errors {
+        log::warn!("{error}");
+    }
+
+    println!(
+        "Dumped {} files, {} anime, {} episodes, {} groups, {} watch progress and {} platform \
+         links, {} problems",
+        summary.files,
+        summary.anime,
+        summary.episodes,
+        summary.groups,
+        summary.watch_progress,
+        summary.platform_links,
+        summary.errors.len()
+    );
+}
+
+fn print_plan(plan: &indexer::dry_run::Plan) {
+    for path in &plan.hash {
+        println!("hash  {}", path.display());
+    }
+
+    for (path, skip) in &plan.skipped {
+        println!("skip  {} ({skip})", path.display());
+    }
+
+    let secs = plan.estimate.as_secs();
+    println!(
+        "{} files to hash ({:.1} GiB), {} skipped",
+        plan.hash.len(),
+        plan.hash_bytes as f64 / (1u64 << 30) as f64,
+        plan.skipped.len()
+    );
+    println!(
+        "At least {} AniDB queries, taking {}h {:02}m {:02}s",
+        plan.queries,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+}
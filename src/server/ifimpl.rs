@@ -2,7 +2,10 @@ use anyhow::{Context as _, Result};
 use tarpc::context::Context;
 
 use super::interface::{Error, TetsuServer};
-use crate::anidb::records::{Anime, Episode};
+use crate::anidb::{
+    records::{Anime, Episode},
+    Anidb, Priority,
+};
 
 #[derive(Clone)]
 pub struct Server;
@@ -50,4 +53,11 @@ impl TetsuServer for Server {
 
         Ok(episodes)
     }
+
+    async fn anime_description(self, _: Context, aid: u32) -> Result<Option<String>, Error> {
+        Ok(Anidb::new()
+            .with_priority(Priority::Interactive)
+            .anime_description(aid)
+            .await?)
+    }
 }
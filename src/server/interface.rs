@@ -8,6 +8,7 @@ use crate::anidb::records::{Anime, Episode};
 pub trait TetsuServer {
     async fn anime() -> Result<Vec<Anime>, Error>;
     async fn episodes(aid: u32) -> Result<Vec<Episode>, Error>;
+    async fn anime_description(aid: u32) -> Result<Option<String>, Error>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
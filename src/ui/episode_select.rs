@@ -15,6 +15,7 @@ use unicode_width::UnicodeWidthStr;
 use super::{enter_alt_screen, leave_alt_screen};
 use crate::{
     anidb::records::{Anime, Episode, File, Group},
+    indexer::link,
     mpv::{Loadfile, LoadfileMode, Mpv, SetProperty, Stop},
 };
 
@@ -59,6 +60,7 @@ impl EpisodeSelect {
             (alpha.to_string(), num)
         });
 
+        let linked = link::linked_files(anime.aid).await?;
         let mut listings = vec![];
 
         for episode in episodes {
@@ -77,19 +79,32 @@ impl EpisodeSelect {
                 let group: Group =
                     serde_json::from_str(&row.gjson).context("Invalid record in database")?;
 
-                let paths_on_disk =
-                    sqlx::query_scalar!("SELECT path FROM indexed_files WHERE fid = ?", file.fid)
-                        .fetch_all(db)
-                        .await?;
+                let paths_on_disk = sqlx::query_scalar!(
+                    "SELECT path FROM indexed_files WHERE fid = ? AND missing_since IS NULL",
+                    file.fid
+                )
+                .fetch_all(db)
+                .await?;
 
                 Result::<_, anyhow::Error>::Ok(FileListing { file, group, paths_on_disk })
             });
 
-            let files = tokio_stream::iter(queries)
+            let mut files: Vec<FileListing> = tokio_stream::iter(queries)
                 .buffer_unordered(10)
                 .try_collect()
                 .await?;
 
+            files.extend(
+                linked
+                    .iter()
+                    .filter(|linked| linked.file.eid == episode.eid)
+                    .map(|linked| FileListing {
+                        file: linked.file.clone(),
+                        group: linked.group.clone(),
+                        paths_on_disk: vec![linked.path.clone()],
+                    }),
+            );
+
             listings.push(EpisodeListing { episode, files });
         }
 
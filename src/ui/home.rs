@@ -11,7 +11,7 @@ use crossterm::{
 use tokio_stream::StreamExt;
 use unicode_width::UnicodeWidthStr;
 
-use super::episode_select::EpisodeSelect;
+use super::{episode_select::EpisodeSelect, watch_order::WatchOrder};
 use crate::anidb::records::Anime;
 
 pub struct Home {
@@ -87,6 +87,10 @@ impl Home {
                         let anime = self.anime[self.selected].clone();
                         EpisodeSelect::new(anime).await?.run().await?;
                     }
+                    KeyCode::Char('w') => {
+                        let anime = self.anime[self.selected].clone();
+                        WatchOrder::new(anime).await?.run().await?;
+                    }
                     ev => println!("{ev:?}"),
                 },
                 Some(Err(err)) => return Err(err.into()),
@@ -11,6 +11,7 @@ use self::home::Home;
 
 mod episode_select;
 mod home;
+mod watch_order;
 
 pub fn enter_alt_screen() -> Result<()> {
     stdout()
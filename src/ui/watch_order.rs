@@ -0,0 +1,151 @@
+use std::{
+    collections::HashSet,
+    io::{stdout, Write},
+};
+
+use anyhow::Result;
+use crossterm::{
+    cursor::MoveTo,
+    event::{Event, EventStream, KeyCode},
+    style::{PrintStyledContent, Stylize},
+    terminal::{self, Clear, ClearType},
+    QueueableCommand,
+};
+use tokio_stream::StreamExt;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use super::episode_select::EpisodeSelect;
+use crate::anidb::{
+    records::Anime,
+    relations::{RelationGraph, RelationType},
+    Anidb, Priority,
+};
+
+/// Prequels and sequels of an anime, in the order they're meant to be watched
+pub struct WatchOrder {
+    anime: Vec<Anime>,
+    owned: HashSet<u32>,
+    selected: usize,
+}
+
+impl WatchOrder {
+    pub async fn new(anime: Anime) -> Result<Self> {
+        let mut anidb = Anidb::new().with_priority(Priority::Interactive);
+
+        let graph = RelationGraph::load(&mut anidb, anime.aid, |relation| {
+            matches!(relation, RelationType::Prequel | RelationType::Sequel)
+        })
+        .await?;
+
+        let order: Vec<Anime> = graph.watch_order().into_iter().cloned().collect();
+        let selected = order.iter().position(|a| a.aid == anime.aid).unwrap_or(0);
+
+        let owned = sqlx::query_scalar!(
+            "SELECT DISTINCT f.aid FROM indexed_files if
+            INNER JOIN files f ON if.fid = f.fid"
+        )
+        .fetch_all(crate::DB.get().await)
+        .await?
+        .into_iter()
+        .map(|aid| aid as u32)
+        .collect();
+
+        Ok(Self { anime: order, owned, selected })
+    }
+
+    pub async fn display(&self) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = width as usize;
+
+        let mut stdout = stdout();
+
+        stdout
+            .queue(Clear(ClearType::All))?
+            .queue(MoveTo(0, 0))?
+            .queue(PrintStyledContent("Watch order".blue()))?;
+
+        for (i, anime) in self
+            .anime
+            .iter()
+            .enumerate()
+            .take((height as usize).saturating_sub(2))
+        {
+            let owned = if self.owned.contains(&anime.aid) {
+                "*"
+            } else {
+                " "
+            };
+
+            let title = format!("{owned} {} ({})", anime.romaji_name, anime.year);
+            let title = truncate(&title, width);
+
+            stdout
+                .queue(MoveTo(0, 2 + i as u16))?
+                .queue(PrintStyledContent(if i == self.selected {
+                    title.black().bold().on_blue()
+                } else if self.owned.contains(&anime.aid) {
+                    title.blue()
+                } else {
+                    title.dark_grey()
+                }))?;
+        }
+
+        stdout.flush()?;
+
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut stdin = EventStream::new();
+
+        loop {
+            self.display().await?;
+
+            match stdin.next().await {
+                Some(Ok(Event::Key(key))) => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char('j') | KeyCode::Down => {
+                        self.selected = (self.selected + 1).min(self.anime.len().saturating_sub(1));
+                    }
+                    KeyCode::Char('k') | KeyCode::Up => {
+                        self.selected = self.selected.saturating_sub(1);
+                    }
+                    KeyCode::Enter => {
+                        let Some(anime) = self.anime.get(self.selected) else {
+                            continue;
+                        };
+
+                        if self.owned.contains(&anime.aid) {
+                            EpisodeSelect::new(anime.clone()).await?.run().await?;
+                        }
+                    }
+                    _ => {}
+                },
+                Some(Ok(Event::Resize(_, _))) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => break,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The longest start of `s` that fits in `width` terminal columns
+fn truncate(s: &str, width: usize) -> &str {
+    if s.width() <= width {
+        return s;
+    }
+
+    let mut used = 0;
+    let end = s
+        .char_indices()
+        .find(|(_, c)| {
+            used += c.width().unwrap_or(0);
+            used > width
+        })
+        .map_or(s.len(), |(i, _)| i);
+
+    &s[..end]
+}